@@ -0,0 +1,104 @@
+#[macro_use]
+extern crate log;
+
+use argh::FromArgs;
+use anyhow::{anyhow, Result};
+use env_logger::Builder;
+use log::LevelFilter;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::time::Duration;
+
+#[derive(FromArgs)]
+/// Animate a color cycling ILBM image, writing it out as a GIF
+struct Opts {
+    /// whether or not to show debug output
+    #[argh(switch, short = 'v')]
+    verbose: bool,
+
+    /// longest animation to write, in seconds, if the cycles take longer to come
+    /// back round they are cut short (default 30)
+    #[argh(option, short = 's', default = "30")]
+    seconds: u64,
+
+    /// the image to animate
+    #[argh(positional)]
+    input: String,
+
+    /// where to write the GIF
+    #[argh(positional)]
+    output: String,
+}
+
+fn main() -> Result<()> {
+    let opts: Opts = argh::from_env();
+
+    let mut builder = Builder::from_default_env();
+
+    if opts.verbose {
+        builder.filter(None, LevelFilter::Debug);
+    }
+
+    builder.init();
+
+    let options = ilbm::ReadOptions {
+        pixel_format: ilbm::PixelFormat::Indexed,
+        ..Default::default()
+    };
+    let image = ilbm::read_from_file(&opts.input, options)?;
+
+    // Just long enough to loop smoothly, if we can
+    let longest = Duration::from_secs(opts.seconds);
+    let duration = match image.cycle_loop() {
+        Some(duration) if duration <= longest => duration,
+        Some(duration) => {
+            warn!("Cycles take {:?} to come round, cutting short at {:?}", duration, longest);
+            longest
+        }
+        None => {
+            warn!("Nothing cycles, writing a still image");
+            Duration::from_secs(1)
+        }
+    };
+
+    let frames = image.cycle_frames(duration)?;
+    info!("{} frames, over {:?}", frames.len(), duration);
+
+    let too_big = |_| anyhow!("{} is too big for a GIF", image.size);
+    let width = u16::try_from(image.size.width()).map_err(too_big)?;
+    let height = u16::try_from(image.size.height()).map_err(too_big)?;
+
+    let mut encoder = gif::Encoder::new(File::create(&opts.output)?, width, height, &[])?;
+    encoder.set_repeat(gif::Repeat::Infinite)?;
+
+    // GIF delays are in hundredths of a second, so round where each frame ends,
+    // rather than each delay, to keep in step over a long animation
+    let mut start = Duration::default();
+    let mut shown = 0;
+
+    for frame in frames {
+        start += frame.delay;
+        let end = ((start.as_millis() + 5) / 10) as u64;
+
+        // Indexed pixels never go past 255, so the rest of a bigger palette is unused
+        if frame.palette.len() > 256 {
+            warn!("A GIF holds at most 256 colors, dropping the last {}", frame.palette.len() - 256);
+        }
+
+        let palette: Vec<u8> = frame
+            .palette
+            .iter()
+            .take(256)
+            .flat_map(|color| vec![color.red(), color.green(), color.blue()])
+            .collect();
+
+        let mut gif_frame = gif::Frame::from_palette_pixels(width, height, frame.pixels, palette, None);
+        gif_frame.delay = u16::try_from(end - shown).unwrap_or(u16::MAX);
+        shown = end;
+
+        encoder.write_frame(&gif_frame)?;
+    }
+
+    println!("Wrote {} ({:?} of animation)", opts.output, duration);
+    Ok(())
+}
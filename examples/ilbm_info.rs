@@ -20,6 +20,10 @@ struct Opts {
     #[argh(switch, short = 'p')]
     pixels: bool,
 
+    /// list every image, for files holding more than one (CAT or LIST)
+    #[argh(switch, short = 'a')]
+    all: bool,
+
     #[argh(positional)]
     files: Vec<String>,
 }
@@ -53,8 +57,35 @@ fn main() -> Result<()> {
         let name = path.to_string_lossy();
         info!("Loading {}", name);
 
-        let image_result = 
-            ilbm::read_from_file( &path, ilbm::ReadOptions{ read_pixels: opts.pixels, page_scale: true});
+        let options = ilbm::ReadOptions{ read_pixels: opts.pixels, page_scale: true, ..Default::default()};
+
+        if opts.all {
+            match ilbm::read_all_from_file(&path, options) {
+                Ok(images) => {
+                    for (position, image_result) in images {
+                        match image_result {
+                            Ok(image) => println!("{} {} {}", image, name, position),
+                            Err(e) => {
+                                failed += 1;
+                                println!("ERROR! {} {} {}", e, name, position)
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    failed += 1;
+                    println!("ERROR! {} {}", e, name)
+                }
+            }
+            continue;
+        }
+
+        // Without pixels, we only need the headers, so don't bother reading the rest of the file
+        let image_result = if opts.pixels {
+            ilbm::read_from_file(&path, options)
+        } else {
+            ilbm::probe_from_file(&path, options)
+        };
 
         match image_result {
             Ok(image) => println!("{} {}", image, name),
@@ -78,7 +109,7 @@ fn main() -> Result<()> {
 fn all_files(paths: &[String]) -> Result<Vec<PathBuf>> {
     let mut files: Vec<PathBuf> = Vec::new();
     for arg in paths {
-        get_files(&Path::new(arg), &mut files)?;
+        get_files(Path::new(arg), &mut files)?;
     }
     Ok(files)
 }
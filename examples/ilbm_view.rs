@@ -57,10 +57,11 @@ fn load_and_show_image(path: &PathBuf, window: &Window) -> Result<()> {
     let name = path.to_string_lossy();
     println!("Loading {}", name);
     let image_result = ilbm::read_from_file(
-        &path,
+        path,
         ilbm::ReadOptions {
             read_pixels: true,
             page_scale: true,
+            ..Default::default()
         },
     );
 
@@ -90,7 +91,7 @@ fn load_and_show_image(path: &PathBuf, window: &Window) -> Result<()> {
 fn args_to_file_list() -> Result<Vec<PathBuf>> {
     let mut files: Vec<PathBuf> = Vec::new();
     for arg in env::args().skip(1) {
-        get_files(&Path::new(&arg), &mut files)?;
+        get_files(Path::new(&arg), &mut files)?;
     }
     Ok(files)
 }
@@ -39,9 +39,234 @@ pub fn unpacker(input: &[u8], byte_width: usize) -> Result<(&[u8], Vec<u8>)> {
     }
 }
 
+/// ByteRun2 (also known as VDAT), from Atari ST DeluxePaint, packs each plane on its own,
+/// in a VDAT chunk, working down each column of words in turn, rather than along rows.
+/// A VDAT starts with a count of command bytes (plus the two bytes of the count itself),
+/// then the commands, then the words they use
+///
+///   LOOP until produced the whole plane
+///       Read the next command byte into n
+///       SELECT n FROM
+///           0           => read a count word, copy that many words literally
+///           1           => read a count word, replicate the next word that many times
+///           [2..127]    => replicate the next word n times
+///           [-1..-128]  => copy the next -n words literally
+///           ENDCASE;
+///       ENDLOOP;
+///
+/// The plane is returned as rows, the same as an uncompressed ACBM plane
+pub fn vertical_unpacker(vdat: &[u8], row_stride: usize, height: usize) -> Result<Vec<u8>> {
+    let mut data = vdat;
+    let command_count = (data.get_u16()? as usize).saturating_sub(2);
+
+    if data.len() < command_count {
+        return Err(IlbmError::NoData);
+    }
+    let (mut commands, mut words) = data.split_at(command_count);
+
+    let mut plane = vec![0u8; row_stride * height];
+    let total = (row_stride / 2) * height;
+    let mut produced = 0;
+
+    // Words go down a column, then on to the top of the next one
+    let mut put = |word: u16, produced: &mut usize| {
+        let (column, y) = (*produced / height, *produced % height);
+        let offset = y * row_stride + column * 2;
+        plane[offset..offset + 2].copy_from_slice(&word.to_be_bytes());
+        *produced += 1;
+    };
+
+    while produced < total {
+        let n = commands.get_i8()?;
+
+        let (count, repeat) = match n {
+            0 => (words.get_u16()? as usize, false),
+            1 => (words.get_u16()? as usize, true),
+            2..=127 => (n as usize, true),
+            _ => (-(n as isize) as usize, false),
+        };
+
+        if count > total - produced {
+            return Err(IlbmError::InvalidData(format!(
+                "VDAT unpacked too many words, expected {} but got {}",
+                total,
+                produced + count
+            )));
+        }
+
+        if repeat {
+            let word = words.get_u16()?;
+            for _ in 0..count {
+                put(word, &mut produced);
+            }
+        } else {
+            for _ in 0..count {
+                put(words.get_u16()?, &mut produced);
+            }
+        }
+    }
+
+    Ok(plane)
+}
+
+/// Which strategy to use when packing rows with ByteRun1
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Packer {
+    /// Smallest possible output for every row
+    #[default]
+    Optimal,
+    /// Byte for byte the same output as PackRow, from the original EA IFF code,
+    /// which some old readers depend on, for example by never expecting a run
+    /// to be split across a literal
+    Amiga,
+}
+
+/// Pack a single row, the inverse of unpacker. Runs never cross the end of
+/// the row, so rows can be unpacked one at a time, exactly as read
+pub fn packer(row: &[u8], packer: Packer, packed: &mut Vec<u8>) {
+    match packer {
+        Packer::Optimal => pack_optimal(row, packed),
+        Packer::Amiga => pack_amiga(row, packed),
+    }
+}
+
+const MAX_RUN: usize = 128;
+
+fn push_literal(literal: &[u8], packed: &mut Vec<u8>) {
+    packed.push((literal.len() - 1) as u8);
+    packed.extend_from_slice(literal);
+}
+
+fn push_repeat(count: usize, byte: u8, packed: &mut Vec<u8>) {
+    packed.push((1 - count as i16) as u8);
+    packed.push(byte);
+}
+
+/// Dynamic programming over the row, cost[i] is the fewest bytes that can
+/// encode the first i bytes. A literal of n bytes costs n+1, a repeat costs 2
+fn pack_optimal(row: &[u8], packed: &mut Vec<u8>) {
+    #[derive(Clone, Copy)]
+    enum Step {
+        Literal(usize),
+        Repeat(usize),
+    }
+
+    let len = row.len();
+    let mut cost = vec![usize::MAX; len + 1];
+    let mut step = vec![Step::Literal(0); len + 1];
+    cost[0] = 0;
+
+    // Length of the run of identical bytes ending at i
+    let mut run = 0;
+
+    for i in 1..=len {
+        run = if i > 1 && row[i - 1] == row[i - 2] { run + 1 } else { 1 };
+
+        for n in 1..=i.min(MAX_RUN) {
+            let c = cost[i - n] + n + 1;
+            if c < cost[i] {
+                cost[i] = c;
+                step[i] = Step::Literal(n);
+            }
+        }
+
+        for n in 2..=run.min(MAX_RUN) {
+            let c = cost[i - n] + 2;
+            if c < cost[i] {
+                cost[i] = c;
+                step[i] = Step::Repeat(n);
+            }
+        }
+    }
+
+    // Walk back from the end to find the steps we took
+    let mut steps = Vec::new();
+    let mut i = len;
+    while i > 0 {
+        steps.push((i, step[i]));
+        i -= match step[i] {
+            Step::Literal(n) | Step::Repeat(n) => n,
+        };
+    }
+
+    for (end, step) in steps.into_iter().rev() {
+        match step {
+            Step::Literal(n) => push_literal(&row[end - n..end], packed),
+            Step::Repeat(n) => push_repeat(n, row[end - 1], packed),
+        }
+    }
+}
+
+/// A direct translation of PackRow, by Jerry Morrison and Steve Shaw, Electronic Arts.
+/// It gathers bytes in a buffer, as literals, switching to a run once it sees
+/// three of the same (or two, at the start of the buffer)
+fn pack_amiga(row: &[u8], packed: &mut Vec<u8>) {
+    const MIN_RUN: usize = 3;
+
+    #[derive(PartialEq)]
+    enum Mode {
+        Dump,
+        Run,
+    }
+
+    if row.is_empty() {
+        return;
+    }
+
+    let mut buf = Vec::<u8>::with_capacity(MAX_RUN + 1);
+    let mut mode = Mode::Dump;
+    let mut run_start = 0;
+    let mut last = row[0];
+    buf.push(last);
+
+    for &c in &row[1..] {
+        buf.push(c);
+
+        match mode {
+            Mode::Dump => {
+                if buf.len() > MAX_RUN {
+                    // Buffer is full, write out all but the byte we just added
+                    push_literal(&buf[..buf.len() - 1], packed);
+                    buf.clear();
+                    buf.push(c);
+                    run_start = 0;
+                } else if c == last {
+                    if buf.len() - run_start >= MIN_RUN {
+                        if run_start > 0 {
+                            push_literal(&buf[..run_start], packed);
+                        }
+                        mode = Mode::Run;
+                    } else if run_start == 0 {
+                        // Nothing to dump, so we cannot lose by making these two a run
+                        mode = Mode::Run;
+                    }
+                } else {
+                    run_start = buf.len() - 1;
+                }
+            }
+            Mode::Run => {
+                if c != last || buf.len() - run_start > MAX_RUN {
+                    push_repeat(buf.len() - 1 - run_start, last, packed);
+                    buf.clear();
+                    buf.push(c);
+                    run_start = 0;
+                    mode = Mode::Dump;
+                }
+            }
+        }
+
+        last = c;
+    }
+
+    match mode {
+        Mode::Dump => push_literal(&buf, packed),
+        Mode::Run => push_repeat(buf.len() - run_start, last, packed),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::unpacker;
+    use super::{packer, unpacker, vertical_unpacker, Packer};
 
     #[test]
     fn unpack_1() {
@@ -111,4 +336,128 @@ mod tests {
         let compressed = [250u8, 10u8]; // Broken, will generate too much data
         let (_remaining, _unpacked) = unpacker(&compressed, 1).unwrap();
     }
-}
\ No newline at end of file
+
+    /// A small xorshift generator, so the property tests are repeatable
+    struct Random(u32);
+
+    impl Random {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        /// A row with a mixture of noise and runs, of various lengths
+        fn row(&mut self, width: usize) -> Vec<u8> {
+            let mut row = Vec::with_capacity(width);
+            while row.len() < width {
+                let byte = self.next() as u8;
+                let count = match self.next() % 4 {
+                    0 => 1,
+                    1 => 2,
+                    2 => 1 + self.next() as usize % 8,
+                    _ => 1 + self.next() as usize % 300,
+                };
+                for _ in 0..count.min(width - row.len()) {
+                    row.push(byte);
+                }
+            }
+            row
+        }
+    }
+
+    fn pack(row: &[u8], mode: Packer) -> Vec<u8> {
+        let mut packed = Vec::new();
+        packer(row, mode, &mut packed);
+        packed
+    }
+
+    #[test]
+    fn pack_round_trip() {
+        let mut random = Random(0x1234_5678);
+
+        for width in (1..300).chain(vec![640, 1024, 4096]) {
+            for _ in 0..4 {
+                let row = random.row(width);
+
+                let optimal = pack(&row, Packer::Optimal);
+                let amiga = pack(&row, Packer::Amiga);
+
+                for packed in &[&optimal, &amiga] {
+                    let (remaining, unpacked) = unpacker(packed, width).unwrap();
+                    assert_eq!(unpacked, row);
+                    assert_eq!(remaining.len(), 0);
+                }
+
+                assert!(optimal.len() <= amiga.len());
+            }
+        }
+    }
+
+    #[test]
+    fn pack_optimal() {
+        assert_eq!(pack(&[], Packer::Optimal), vec![]);
+        assert_eq!(pack(&[7], Packer::Optimal), vec![0, 7]);
+        assert_eq!(pack(&[7, 7], Packer::Optimal), vec![255, 7]);
+        assert_eq!(pack(&[1, 2, 3], Packer::Optimal), vec![2, 1, 2, 3]);
+        assert_eq!(pack(&[9; 128], Packer::Optimal), vec![129, 9]);
+        assert_eq!(pack(&[9; 129], Packer::Optimal), vec![129, 9, 0, 9]);
+        assert_eq!(pack(&[1, 5, 5, 5, 5, 2], Packer::Optimal).len(), 6);
+    }
+
+    #[test]
+    fn pack_amiga() {
+        assert_eq!(pack(&[7], Packer::Amiga), vec![0, 7]);
+        assert_eq!(pack(&[1, 1, 1, 2, 3], Packer::Amiga), vec![254, 1, 1, 2, 3]);
+
+        // A run of two inside a literal is left alone
+        assert_eq!(pack(&[1, 2, 2, 3], Packer::Amiga), vec![3, 1, 2, 2, 3]);
+
+        // but three is enough to break out of the literal
+        assert_eq!(pack(&[1, 2, 2, 2, 3], Packer::Amiga), vec![0, 1, 254, 2, 0, 3]);
+
+        // Long runs and literals are split at 128 bytes
+        assert_eq!(pack(&[9; 130], Packer::Amiga), vec![129, 9, 255, 9]);
+        let literal: Vec<u8> = (0..130).map(|i| i as u8).collect();
+        let packed = pack(&literal, Packer::Amiga);
+        assert_eq!(packed[0], 127);
+        assert_eq!(packed[129], 1);
+        assert_eq!(packed.len(), 132);
+    }
+
+    #[test]
+    fn vertical_unpack() {
+        // Two columns of words, three rows, so six words
+        let vdat = [
+            0, 6, // four commands
+            0xfe, // copy two words
+            2,    // repeat a word twice
+            0,    // copy a count of words
+            1,    // repeat a count of times, which is none
+            0x12, 0x34, 0x56, 0x78, // the two copied words
+            0xaa, 0xbb, // the repeated word
+            0, 2, 0x01, 0x02, 0x03, 0x04, // copy two words
+            0, 0, 0xff, 0xff, // repeat nothing
+        ];
+        let plane = vertical_unpacker(&vdat, 4, 3).unwrap();
+
+        #[rustfmt::skip]
+        assert_eq!(plane, [
+            0x12, 0x34, 0xaa, 0xbb,
+            0x56, 0x78, 0x01, 0x02,
+            0xaa, 0xbb, 0x03, 0x04,
+        ]);
+    }
+
+    #[test]
+    fn vertical_unpack_broken() {
+        // Too many words
+        assert!(vertical_unpacker(&[0, 3, 7, 0, 1], 2, 3).is_err());
+
+        // Not enough commands, or words
+        assert!(vertical_unpacker(&[0, 3, 2, 0, 1], 2, 3).is_err());
+        assert!(vertical_unpacker(&[0, 3, 0xfd, 0, 1], 2, 3).is_err());
+        assert!(vertical_unpacker(&[0, 9, 0xfd], 2, 3).is_err());
+    }
+}
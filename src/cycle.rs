@@ -0,0 +1,564 @@
+use crate::bytes::BigEndian;
+use crate::iff::IffChunk;
+use crate::*;
+use std::time::Duration;
+
+//
+// Color cycling, where a range of the color map is rotated over time, to animate a
+// still picture (waterfalls, fire and so on). DeluxePaint stores each range in a CRNG,
+// Graphicraft in a CCRT, and DeluxePaint IV in a DRNG, which can also hold true colors
+//
+
+/// A rate of 16384 is one step every vertical blank, 60 steps a second
+pub const RATE_ONE_STEP_PER_TICK: u32 = 16384;
+
+/// Vertical blanks a second, the clock that cycling runs from (on NTSC at least)
+pub const TICKS_PER_SECOND: u32 = 60;
+
+/// Flags, from CRNG and DRNG
+const RNG_ACTIVE: u16 = 1;
+const RNG_REVERSE: u16 = 2;
+
+/// Which chunk a cycle came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CycleKind {
+    /// DeluxePaint
+    Crng,
+    /// Graphicraft
+    Ccrt,
+    /// DeluxePaint IV, which may include true color cells
+    Drng,
+}
+
+/// One range of colors to cycle
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorCycle {
+    pub kind: CycleKind,
+
+    /// The first and last color map index (or DRNG cell) of the range
+    pub low: usize,
+    pub high: usize,
+
+    /// How fast to cycle, where 16384 is 60 steps a second. For a CCRT this is
+    /// as near as a rate can get to its delay
+    pub rate: u32,
+
+    /// CCRT only, the time between steps, which it gives rather than a rate
+    pub delay: Option<Duration>,
+
+    /// Cycle from high to low, rather than low to high
+    pub reverse: bool,
+
+    /// Inactive ranges are stored, but don't cycle
+    pub active: bool,
+
+    /// DRNG only, cells holding a fixed color, as (cell, color)
+    pub true_colors: Vec<(usize, RgbValue)>,
+
+    /// DRNG only, cells showing a color register, as (cell, color map index)
+    pub registers: Vec<(usize, usize)>,
+}
+
+impl ColorCycle {
+    /// Steps taken per second, usually fractional
+    pub fn steps_per_second(&self) -> f64 {
+        match self.delay {
+            Some(delay) => 1.0 / delay.as_secs_f64(),
+            None => self.rate as f64 * TICKS_PER_SECOND as f64 / RATE_ONE_STEP_PER_TICK as f64,
+        }
+    }
+
+    /// Whether this range changes anything at all, as it can be inactive, never step, or be empty
+    pub fn is_cycling(&self) -> bool {
+        self.active && (self.rate > 0 || self.delay.is_some()) && self.high > self.low
+    }
+
+    /// How many steps have been taken by the given time. As on the Amiga, the rate is added
+    /// up every vertical blank, with a step whenever it passes 16384. A CCRT delay is exact
+    pub fn steps_at(&self, time: Duration) -> u64 {
+        if !self.is_cycling() {
+            return 0;
+        }
+
+        if let Some(delay) = self.delay {
+            return (time.as_micros() / delay.as_micros()) as u64;
+        }
+
+        let ticks = time.as_micros() as u64 * TICKS_PER_SECOND as u64 / 1_000_000;
+        self.steps_at_tick(ticks)
+    }
+
+    /// How many steps have been taken by the given vertical blank
+    fn steps_at_tick(&self, tick: u64) -> u64 {
+        if !self.is_cycling() {
+            return 0;
+        }
+
+        match self.delay {
+            Some(delay) => tick * 1_000_000 / (TICKS_PER_SECOND as u64 * delay.as_micros() as u64),
+            None => tick * self.rate as u64 / RATE_ONE_STEP_PER_TICK as u64,
+        }
+    }
+
+    /// The number of colors (or cells) that go round, given the size of the color map
+    fn len(&self, map_size: usize) -> usize {
+        let high = match self.kind {
+            CycleKind::Drng => self.high,
+            CycleKind::Crng | CycleKind::Ccrt => self.high.min(map_size.saturating_sub(1)),
+        };
+        (high + 1).saturating_sub(self.low)
+    }
+
+    /// Vertical blanks until the range is back where it started, None if it never moves
+    fn period(&self, map_size: usize) -> Option<u64> {
+        let len = self.len(map_size) as u64;
+        if !self.is_cycling() || len < 2 {
+            return None;
+        }
+
+        // Steps are taken when the ticks, scaled by what a step costs, pass a whole
+        // number, so we need the first tick where that is a whole number of turns
+        let (per_step, per_tick) = match self.delay {
+            Some(delay) => (TICKS_PER_SECOND as u64 * delay.as_micros() as u64, 1_000_000),
+            None => (RATE_ONE_STEP_PER_TICK as u64, self.rate as u64),
+        };
+        let turn = per_step.checked_mul(len)?;
+        Some(turn / gcd(turn, per_tick))
+    }
+
+    /// Rotate the colors of this range by the given number of steps
+    pub fn apply(&self, colors: &mut [RgbValue], steps: u64) {
+        if !self.is_cycling() {
+            return;
+        }
+
+        match self.kind {
+            CycleKind::Crng | CycleKind::Ccrt => {
+                // Ranges that fall off the end of the map are cut short
+                let len = self.len(colors.len());
+                if len < 2 {
+                    return;
+                }
+
+                let range = &mut colors[self.low..self.low + len];
+                let shift = (steps % range.len() as u64) as usize;
+                if self.reverse {
+                    range.rotate_left(shift);
+                } else {
+                    range.rotate_right(shift);
+                }
+            }
+            CycleKind::Drng => {
+                // Each cell has a color, fixed or from its register, which move along
+                // the cells, then the registers take the color now in their cell
+                let len = self.high - self.low + 1;
+                let mut cells: Vec<Option<RgbValue>> = vec![None; len];
+
+                for &(cell, color) in &self.true_colors {
+                    if let Some(c) = cells.get_mut(cell.wrapping_sub(self.low)) {
+                        *c = Some(color);
+                    }
+                }
+                for &(cell, index) in &self.registers {
+                    if let (Some(c), Some(&color)) =
+                        (cells.get_mut(cell.wrapping_sub(self.low)), colors.get(index))
+                    {
+                        *c = Some(color);
+                    }
+                }
+
+                let shift = (steps % len as u64) as usize;
+                if self.reverse {
+                    cells.rotate_left(shift);
+                } else {
+                    cells.rotate_right(shift);
+                }
+
+                for &(cell, index) in &self.registers {
+                    let color = cells.get(cell.wrapping_sub(self.low)).copied().flatten();
+                    if let (Some(color), Some(register)) = (color, colors.get_mut(index)) {
+                        *register = color;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// CRNG, from DeluxePaint
+pub(crate) fn read_crng(chunk: IffChunk) -> Result<ColorCycle> {
+    let mut buf = chunk.data();
+
+    let _pad = buf.get_u16()?;
+    let rate = buf.get_u16()? as u32;
+    let flags = buf.get_u16()?;
+    let low = buf.get_u8()? as usize;
+    let high = buf.get_u8()? as usize;
+
+    Ok(ColorCycle {
+        kind: CycleKind::Crng,
+        low,
+        high,
+        rate,
+        delay: None,
+        reverse: flags & RNG_REVERSE != 0,
+        active: flags & RNG_ACTIVE != 0,
+        true_colors: Vec::new(),
+        registers: Vec::new(),
+    })
+}
+
+/// CCRT, from Graphicraft, which gives the time between steps, rather than a rate
+pub(crate) fn read_ccrt(chunk: IffChunk) -> Result<ColorCycle> {
+    let mut buf = chunk.data();
+
+    let direction = buf.get_i16()?;
+    let low = buf.get_u8()? as usize;
+    let high = buf.get_u8()? as usize;
+    let seconds = buf.get_u32()? as u64;
+    let micros = buf.get_u32()? as u64;
+
+    let delay = seconds * 1_000_000 + micros;
+    let per_tick = RATE_ONE_STEP_PER_TICK as u64 * 1_000_000 / TICKS_PER_SECOND as u64;
+    let rate = per_tick.checked_div(delay).unwrap_or(0).min(u32::MAX as u64) as u32;
+    let delay = if delay == 0 {
+        None
+    } else {
+        Some(Duration::from_micros(delay))
+    };
+
+    Ok(ColorCycle {
+        kind: CycleKind::Ccrt,
+        low,
+        high,
+        rate,
+        delay,
+        reverse: direction < 0,
+        active: direction != 0,
+        true_colors: Vec::new(),
+        registers: Vec::new(),
+    })
+}
+
+/// DRNG, from DeluxePaint IV, a range of cells, some with a fixed color, some
+/// showing a color register
+pub(crate) fn read_drng(chunk: IffChunk) -> Result<ColorCycle> {
+    let mut buf = chunk.data();
+
+    let low = buf.get_u8()? as usize;
+    let high = buf.get_u8()? as usize;
+    let rate = buf.get_u16()? as u32;
+    let flags = buf.get_u16()?;
+    let true_count = buf.get_u8()?;
+    let register_count = buf.get_u8()?;
+
+    let true_colors = (0..true_count)
+        .map(|_| {
+            let cell = buf.get_u8()? as usize;
+            Ok((cell, RgbValue(buf.get_u8()?, buf.get_u8()?, buf.get_u8()?)))
+        })
+        .collect::<Result<_>>()?;
+
+    let registers = (0..register_count)
+        .map(|_| Ok((buf.get_u8()? as usize, buf.get_u8()? as usize)))
+        .collect::<Result<_>>()?;
+
+    Ok(ColorCycle {
+        kind: CycleKind::Drng,
+        low,
+        high,
+        rate,
+        delay: None,
+        reverse: flags & RNG_REVERSE != 0,
+        active: flags & RNG_ACTIVE != 0,
+        true_colors,
+        registers,
+    })
+}
+
+/// One frame of a cycling image, the pixels are always the same, only the palette changes
+#[derive(Debug, Clone)]
+pub struct CycleFrame<'a> {
+    /// Color map indexes, exactly as in the image
+    pub pixels: &'a [u8],
+    pub palette: Vec<RgbValue>,
+    /// How long to show this frame, in whole vertical blanks, as that is how the Amiga did it
+    pub delay: Duration,
+}
+
+/// Vertical blanks until every cycle is back where it started, at the same time
+pub(crate) fn loop_ticks(cycles: &[ColorCycle], map_size: usize) -> Option<u64> {
+    cycles
+        .iter()
+        .filter_map(|cycle| cycle.period(map_size))
+        .try_fold(None, |acc: Option<u64>, period| match acc {
+            None => Some(Some(period)),
+            Some(acc) => acc.checked_mul(period / gcd(acc, period)).map(Some),
+        })
+        .flatten()
+}
+
+pub(crate) fn ticks_to_duration(ticks: u64) -> Duration {
+    let per_second = TICKS_PER_SECOND as u64;
+    Duration::from_secs(ticks / per_second)
+        + Duration::from_nanos(ticks % per_second * 1_000_000_000 / per_second)
+}
+
+/// Whole vertical blanks in a duration, rounded, so a duration made by
+/// ticks_to_duration comes back as the same ticks
+fn duration_to_ticks(duration: Duration) -> u64 {
+    ((duration.as_nanos() * TICKS_PER_SECOND as u128 + 500_000_000) / 1_000_000_000) as u64
+}
+
+/// Every change of palette over the duration, one frame for each, taking as long
+/// as it is shown. The palette is worked out once every vertical blank
+pub(crate) fn frames(image: &IlbmImage, duration: Duration) -> Result<Vec<CycleFrame<'_>>> {
+    let color_map = match (&image.color_map, image.pixel_format) {
+        (Some(color_map), PixelFormat::Indexed) => color_map,
+        _ => {
+            return Err(IlbmError::NotSupported(
+                "Cycling frames for images not read as Indexed".to_string(),
+            ))
+        }
+    };
+
+    let ticks = duration_to_ticks(duration).max(1);
+    let mut frames = Vec::<CycleFrame>::new();
+    let mut frame_start = 0;
+
+    for tick in 0..ticks {
+        let mut palette = color_map.colors.clone();
+        for cycle in &image.color_cycles {
+            cycle.apply(&mut palette, cycle.steps_at_tick(tick));
+        }
+
+        if frames.last().map(|frame| frame.palette != palette).unwrap_or(true) {
+            if let Some(frame) = frames.last_mut() {
+                frame.delay = ticks_to_duration(tick - frame_start);
+            }
+            frame_start = tick;
+            frames.push(CycleFrame {
+                pixels: &image.pixels,
+                palette,
+                delay: Duration::default(),
+            });
+        }
+    }
+
+    if let Some(frame) = frames.last_mut() {
+        frame.delay = ticks_to_duration(ticks - frame_start);
+    }
+
+    Ok(frames)
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// The colors of the map, after every cycle has run for the given time
+pub(crate) fn colors_at(colors: &[RgbValue], cycles: &[ColorCycle], time: Duration) -> Vec<RgbValue> {
+    let mut colors = colors.to_vec();
+    for cycle in cycles {
+        cycle.apply(&mut colors, cycle.steps_at(time));
+    }
+    colors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read::tests::{ilbm_bytes, insert_chunks, read_test, TestImage};
+
+    fn gray_map(len: u8) -> Vec<u8> {
+        (0..len).flat_map(|i| [i, i, i]).collect()
+    }
+
+    /// An image with 8 grays, and the given cycling chunks
+    fn cycle_bytes(chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let bytes = ilbm_bytes(&TestImage {
+            width: 2,
+            height: 1,
+            planes: 3,
+            cmap: Some(gray_map(8)),
+            pixels: vec![1, 2],
+            ..Default::default()
+        });
+        insert_chunks(bytes, chunks)
+    }
+
+    fn crng_data(rate: u16, flags: u16, low: u8, high: u8) -> Vec<u8> {
+        let mut data = vec![0, 0];
+        data.extend_from_slice(&rate.to_be_bytes());
+        data.extend_from_slice(&flags.to_be_bytes());
+        data.extend_from_slice(&[low, high]);
+        data
+    }
+
+    fn grays(values: &[u8]) -> Vec<RgbValue> {
+        values.iter().map(|&v| RgbValue(v, v, v)).collect()
+    }
+
+    #[test]
+    fn crng() {
+        let bytes = cycle_bytes(&[
+            (b"CRNG", crng_data(8192, RNG_ACTIVE, 1, 3)),
+            (b"CRNG", crng_data(16384, RNG_ACTIVE | RNG_REVERSE, 4, 6)),
+            (b"CRNG", crng_data(16384, 0, 0, 7)),
+        ]);
+        let image = read_test(&bytes, PixelFormat::Indexed).unwrap();
+
+        let cycles = &image.color_cycles;
+        assert_eq!(cycles.len(), 3);
+        assert_eq!(cycles[0].kind, CycleKind::Crng);
+        assert_eq!((cycles[0].low, cycles[0].high), (1, 3));
+        assert_eq!(cycles[0].rate, 8192);
+        assert_eq!(cycles[0].steps_per_second(), 30.0);
+        assert!(cycles[1].reverse);
+        assert!(!cycles[2].active);
+
+        assert_eq!(image.palette_at(Duration::from_secs(0)).unwrap(), grays(&[0, 1, 2, 3, 4, 5, 6, 7]));
+
+        // After 1/60th of a second, only the faster range has moved, backwards
+        let tick = Duration::from_micros(16_667);
+        assert_eq!(image.palette_at(tick).unwrap(), grays(&[0, 1, 2, 3, 5, 6, 4, 7]));
+
+        // then both
+        assert_eq!(image.palette_at(tick * 2).unwrap(), grays(&[0, 3, 1, 2, 6, 4, 5, 7]));
+
+        // and all the way round
+        assert_eq!(image.palette_at(tick * 6).unwrap(), grays(&[0, 1, 2, 3, 4, 5, 6, 7]));
+    }
+
+    #[test]
+    fn ccrt() {
+        // Backwards, every half second
+        let mut data = (-1i16).to_be_bytes().to_vec();
+        data.extend_from_slice(&[2, 4]);
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(&500_000u32.to_be_bytes());
+        data.extend_from_slice(&[0, 0]);
+
+        let image = read_test(&cycle_bytes(&[(b"CCRT", data)]), PixelFormat::Indexed).unwrap();
+        let cycle = &image.color_cycles[0];
+        assert_eq!(cycle.kind, CycleKind::Ccrt);
+        assert!(cycle.active && cycle.reverse);
+        assert_eq!(cycle.delay, Some(Duration::from_millis(500)));
+        assert_eq!(cycle.steps_per_second(), 2.0);
+        assert_eq!(cycle.steps_at(Duration::from_millis(1500)), 3);
+
+        assert_eq!(
+            image.palette_at(Duration::from_millis(500)).unwrap(),
+            grays(&[0, 1, 3, 4, 2, 5, 6, 7])
+        );
+    }
+
+    #[test]
+    fn drng() {
+        // Cells 0 to 3, a fixed white in cell 0, registers 5 and 6 in cells 2 and 3
+        let mut data = vec![0, 3];
+        data.extend_from_slice(&16384u16.to_be_bytes());
+        data.extend_from_slice(&RNG_ACTIVE.to_be_bytes());
+        data.extend_from_slice(&[1, 2, 0, 255, 255, 255, 2, 5, 3, 6]);
+
+        let image = read_test(&cycle_bytes(&[(b"DRNG", data)]), PixelFormat::Indexed).unwrap();
+        let cycle = &image.color_cycles[0];
+        assert_eq!(cycle.kind, CycleKind::Drng);
+        assert_eq!(cycle.true_colors, vec![(0, RgbValue(255, 255, 255))]);
+        assert_eq!(cycle.registers, vec![(2, 5), (3, 6)]);
+
+        let tick = Duration::from_micros(16_667);
+
+        // Cell 3 gets the color of cell 2 (register 5), cell 2 that of the empty cell 1
+        let colors = image.palette_at(tick).unwrap();
+        assert_eq!(colors[5], RgbValue(5, 5, 5));
+        assert_eq!(colors[6], RgbValue(5, 5, 5));
+
+        // Two steps brings the white round to cell 2
+        let colors = image.palette_at(tick * 2).unwrap();
+        assert_eq!(colors[5], RgbValue(255, 255, 255));
+        assert_eq!(colors[6], RgbValue(6, 6, 6));
+    }
+
+    #[test]
+    fn probe_and_no_map() {
+        let bytes = cycle_bytes(&[(b"CRNG", crng_data(8192, RNG_ACTIVE, 1, 3))]);
+        let image = probe_from_reader(std::io::Cursor::new(bytes), ReadOptions::default()).unwrap();
+        assert_eq!(image.color_cycles.len(), 1);
+
+        // Without a color map, there is no palette
+        let image = IlbmImage::default();
+        assert!(image.palette_at(Duration::from_secs(1)).is_none());
+    }
+
+    #[test]
+    fn loop_and_frames() {
+        let bytes = cycle_bytes(&[
+            (b"CRNG", crng_data(8192, RNG_ACTIVE, 1, 3)),
+            (b"CRNG", crng_data(16384, RNG_ACTIVE | RNG_REVERSE, 4, 6)),
+            (b"CRNG", crng_data(16384, 0, 0, 7)),
+        ]);
+        let image = read_test(&bytes, PixelFormat::Indexed).unwrap();
+
+        // 2 ticks a step for 3 colors, and 1 for 3, come together after 6 ticks
+        let tick = ticks_to_duration(1);
+        assert_eq!(image.cycle_loop(), Some(ticks_to_duration(6)));
+
+        let frames = image.cycle_frames(ticks_to_duration(6)).unwrap();
+        assert_eq!(frames.len(), 6);
+        assert!(frames.iter().all(|frame| frame.delay == tick));
+        assert!(frames.iter().all(|frame| frame.pixels == &image.pixels[..]));
+        assert_eq!(frames[0].palette, grays(&[0, 1, 2, 3, 4, 5, 6, 7]));
+        assert_eq!(frames[2].palette, grays(&[0, 3, 1, 2, 6, 4, 5, 7]));
+
+        // Slower cycles have longer frames
+        let bytes = cycle_bytes(&[(b"CRNG", crng_data(4096, RNG_ACTIVE, 6, 7))]);
+        let image = read_test(&bytes, PixelFormat::Indexed).unwrap();
+        assert_eq!(image.cycle_loop(), Some(ticks_to_duration(8)));
+
+        let frames = image.cycle_frames(Duration::from_millis(250)).unwrap();
+        let delays: Vec<Duration> = frames.iter().map(|frame| frame.delay).collect();
+        let four = ticks_to_duration(4);
+        assert_eq!(delays, [four, four, four, ticks_to_duration(3)]);
+        assert_eq!(frames[1].palette, grays(&[0, 1, 2, 3, 4, 5, 7, 6]));
+    }
+
+    #[test]
+    fn loop_lengths() {
+        let tick = ticks_to_duration(1);
+
+        // Nothing cycling, nothing to loop, but still a frame
+        let image = read_test(&cycle_bytes(&[]), PixelFormat::Indexed).unwrap();
+        assert_eq!(image.cycle_loop(), None);
+        let frames = image.cycle_frames(Duration::from_secs(1)).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].delay, Duration::from_secs(1));
+
+        // A CCRT of 3 colors, every half second
+        let mut data = 1i16.to_be_bytes().to_vec();
+        data.extend_from_slice(&[2, 4]);
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(&500_000u32.to_be_bytes());
+        data.extend_from_slice(&[0, 0]);
+        let image = read_test(&cycle_bytes(&[(b"CCRT", data)]), PixelFormat::Indexed).unwrap();
+        assert_eq!(image.cycle_loop(), Some(Duration::from_millis(1500)));
+
+        // A range past the end of the map only cycles what is there
+        let bytes = cycle_bytes(&[(b"CRNG", crng_data(16384, RNG_ACTIVE, 6, 20))]);
+        let image = read_test(&bytes, PixelFormat::Indexed).unwrap();
+        assert_eq!(image.cycle_loop(), Some(ticks_to_duration(2)));
+
+        // An odd rate takes a while
+        let bytes = cycle_bytes(&[(b"CRNG", crng_data(5000, RNG_ACTIVE, 0, 7))]);
+        let image = read_test(&bytes, PixelFormat::Indexed).unwrap();
+        assert_eq!(image.cycle_loop(), Some(ticks_to_duration(16384)));
+
+        // Frames need indexes
+        let image = read_test(&bytes, PixelFormat::Rgb).unwrap();
+        assert!(matches!(image.cycle_frames(tick), Err(IlbmError::NotSupported(_))));
+    }
+}
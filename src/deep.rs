@@ -0,0 +1,487 @@
+use crate::bytes::BigEndian;
+use crate::iff::IffChunk;
+use crate::read::apply_alpha;
+use crate::*;
+
+//
+// FORM DEEP, the true color format of TVPaint (and a few others). Rather than
+// bit planes, each pixel is a list of elements, red, green, blue, alpha and so on,
+// in the order given by the DPEL chunk, then DBOD holds the pixels, compressed one
+// of several ways, as given by the DGBL chunk. DCHG only matters to animations
+// (it is the delay between frames) so it is skipped like any unknown chunk.
+//
+// Only uncompressed, run length and TVDC bodies are decoded. The Huffman and
+// dynamic schemes were given numbers, but their layout was never published (TVPaint
+// itself writes run length or TVDC), and JPEG would need a JPEG decoder, so all
+// three are reported as not supported
+//
+
+/// Compression schemes, from DGBL
+const NO_COMPRESSION: u16 = 0;
+const RUN_LENGTH: u16 = 1;
+const HUFFMAN: u16 = 2;
+const DYNAMIC_HUFFMAN: u16 = 3;
+const JPEG: u16 = 4;
+const TVDC: u16 = 5;
+
+/// Element types, from DPEL, we only care about the colors and alpha
+const RED: u16 = 1;
+const GREEN: u16 = 2;
+const BLUE: u16 = 3;
+const ALPHA: u16 = 4;
+
+/// One element of each pixel
+#[derive(Debug, Clone, Copy)]
+struct Element {
+    kind: u16,
+    bits: u16,
+}
+
+/// What we learn about a DEEP image, from the chunks before its DBOD, that
+/// doesn't fit in an IlbmImage
+#[derive(Debug, Default)]
+pub(crate) struct DeepHeader {
+    compression: u16,
+    elements: Vec<Element>,
+
+    /// The deltas used by TVDC compression, from the TVDC chunk
+    tvdc: Option<[i16; 16]>,
+}
+
+impl DeepHeader {
+    /// DGBL, the size of the display, how the pixels are compressed, and their aspect
+    pub(crate) fn read_globals(&mut self, chunk: IffChunk, image: &mut IlbmImage) -> Result<()> {
+        let mut buf = chunk.data();
+
+        image.page_size = Size2D(buf.get_u16()? as usize, buf.get_u16()? as usize);
+        self.compression = buf.get_u16()?;
+        image.pixel_aspect = Size2D(buf.get_u8()? as usize, buf.get_u8()? as usize);
+
+        // The image is the whole display, unless a DLOC says otherwise
+        image.size = image.page_size;
+        // DEEP has its own schemes, none of which an ILBM knows
+        image.compression = if self.compression == NO_COMPRESSION {
+            Compression::None
+        } else {
+            Compression::Deep(self.compression)
+        };
+
+        if image.size.0 == 0 || image.size.1 == 0 {
+            return Err(IlbmError::InvalidHeader {
+                expected: "non-zero height and width".to_string(),
+                actual: format!("{}", image.size),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// DPEL, the elements of each pixel, in order
+    pub(crate) fn read_elements(&mut self, chunk: IffChunk, image: &mut IlbmImage) -> Result<()> {
+        let mut buf = chunk.data();
+
+        let count = buf.get_u32()?;
+        self.elements = (0..count)
+            .map(|_| {
+                Ok(Element {
+                    kind: buf.get_u16()?,
+                    bits: buf.get_u16()?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        image.planes = self.elements.iter().map(|e| e.bits as usize).sum();
+        if image.planes == 0 {
+            return Err(IlbmError::NoPlanes);
+        }
+
+        Ok(())
+    }
+
+    /// DLOC, where the following DBOD goes on the display, we only use its size
+    pub(crate) fn read_location(&mut self, chunk: IffChunk, image: &mut IlbmImage) -> Result<()> {
+        let mut buf = chunk.data();
+
+        let size = Size2D(buf.get_u16()? as usize, buf.get_u16()? as usize);
+        let _x = buf.get_i16()?;
+        let _y = buf.get_i16()?;
+
+        if size.0 == 0 || size.1 == 0 {
+            return Err(IlbmError::InvalidHeader {
+                expected: "non-zero height and width".to_string(),
+                actual: format!("{}", size),
+            });
+        }
+
+        image.size = size;
+        Ok(())
+    }
+
+    /// TVDC, the table of deltas for TVDC compression
+    pub(crate) fn read_tvdc(&mut self, chunk: IffChunk) -> Result<()> {
+        let mut buf = chunk.data();
+
+        let mut table = [0i16; 16];
+        for delta in table.iter_mut() {
+            *delta = buf.get_i16()?;
+        }
+
+        self.tvdc = Some(table);
+        Ok(())
+    }
+}
+
+/// Decode a DBOD into RGB (or RGBA) pixels
+pub(crate) fn read_body(chunk: IffChunk, header: &DeepHeader, image: &mut IlbmImage) -> Result<()> {
+    if header.elements.is_empty() {
+        return Err(IlbmError::NoHeader);
+    }
+
+    if let Some(element) = header.elements.iter().find(|e| e.bits != 8) {
+        return Err(IlbmError::NotSupported(format!(
+            "DEEP elements of {} bits",
+            element.bits
+        )));
+    }
+
+    let position = |kind| header.elements.iter().position(|e| e.kind == kind);
+    let (red, green, blue) = match (position(RED), position(GREEN), position(BLUE)) {
+        (Some(red), Some(green), Some(blue)) => (red, green, blue),
+        _ => {
+            return Err(IlbmError::NotSupported(
+                "DEEP images without red, green and blue".to_string(),
+            ))
+        }
+    };
+
+    let Size2D(width, height) = image.size;
+    let element_count = header.elements.len();
+    let data = chunk.data();
+
+    let elements = match header.compression {
+        NO_COMPRESSION => {
+            let size = width * height * element_count;
+            data.get(..size).ok_or(IlbmError::NoData)?.to_vec()
+        }
+        RUN_LENGTH => unpack_pixels(data, element_count, width * height)?,
+        TVDC => {
+            let table = header.tvdc.as_ref().ok_or_else(|| {
+                IlbmError::InvalidData("TVDC compression, but no TVDC chunk".to_string())
+            })?;
+            unpack_tvdc(data, table, element_count, width, height)?
+        }
+        HUFFMAN | DYNAMIC_HUFFMAN | JPEG => {
+            return Err(IlbmError::NotSupported(format!(
+                "DEEP compression {}",
+                ["", "", "Huffman", "dynamic Huffman", "JPEG"][header.compression as usize]
+            )))
+        }
+        other => {
+            return Err(IlbmError::NotSupported(format!(
+                "Unknown DEEP compression {}",
+                other
+            )))
+        }
+    };
+
+    let mut rgb = Vec::<u8>::with_capacity(3 * width * height);
+    for pixel in elements.chunks(element_count) {
+        rgb.extend_from_slice(&[pixel[red], pixel[green], pixel[blue]]);
+    }
+
+    let alpha: Vec<u8> = match position(ALPHA) {
+        Some(alpha) => elements.chunks(element_count).map(|p| p[alpha]).collect(),
+        None => Vec::new(),
+    };
+
+    image.pixels = apply_alpha(rgb, &alpha, image.pixel_format);
+    Ok(())
+}
+
+/// Run length compression is ByteRun1, but the runs are of whole pixels rather
+/// than bytes, and carry on from one row to the next
+fn unpack_pixels(mut data: &[u8], pixel_size: usize, pixel_count: usize) -> Result<Vec<u8>> {
+    let size = pixel_size * pixel_count;
+
+    // A run of 128 pixels takes at least 2 bytes, so the data can never unpack to more than
+    // 128 times its length, there is no point making room for more, whatever the header says
+    let mut out = Vec::<u8>::with_capacity(size.min(data.len() * 128));
+
+    while out.len() < size {
+        let header = data.get_i8()?;
+
+        match header {
+            0..=127 => {
+                let len = (header as usize + 1) * pixel_size;
+                let literal = data.get(..len).ok_or(IlbmError::NoData)?;
+                out.extend_from_slice(literal);
+                data = &data[len..];
+            }
+            -127..=-1 => {
+                let pixel = data.get(..pixel_size).ok_or(IlbmError::NoData)?;
+                for _ in 0..(1 - header as isize) {
+                    out.extend_from_slice(pixel);
+                }
+                data = &data[pixel_size..];
+            }
+            _ => (),
+        }
+    }
+
+    out.truncate(size);
+    Ok(out)
+}
+
+/// TVDC compression stores each element of a row in turn, as 4 bit indexes into a
+/// table of deltas from the previous value, a zero delta is followed by the number
+/// of times to repeat the value (less one). Each element of a row starts from zero,
+/// on a byte boundary
+fn unpack_tvdc(
+    data: &[u8],
+    table: &[i16; 16],
+    element_count: usize,
+    width: usize,
+    height: usize,
+) -> Result<Vec<u8>> {
+    // A byte is at most a zero delta and a count of 16, so check there could be enough
+    // data before making room for it all, the size in the header may be nonsense
+    let size = width * height * element_count;
+    if size > data.len() * 16 {
+        return Err(IlbmError::NoData);
+    }
+
+    let mut out = vec![0u8; size];
+
+    let nibble = |i: usize| -> Result<usize> {
+        let byte = *data.get(i / 2).ok_or(IlbmError::NoData)?;
+        Ok(if i & 1 == 0 { byte >> 4 } else { byte & 0xf } as usize)
+    };
+
+    let mut i = 0;
+    for y in 0..height {
+        for element in 0..element_count {
+            let mut value = 0u8;
+            let mut x = 0;
+
+            while x < width {
+                let delta = table[nibble(i)?];
+                i += 1;
+
+                let count = if delta != 0 {
+                    value = value.wrapping_add(delta as u8);
+                    1
+                } else {
+                    i += 1;
+                    (nibble(i - 1)? + 1).min(width - x)
+                };
+
+                for _ in 0..count {
+                    out[(y * width + x) * element_count + element] = value;
+                    x += 1;
+                }
+            }
+
+            // Round up to the next byte
+            i = (i + 1) & !1;
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read::tests::{chunk, read_test};
+
+    /// A DEEP file, with the given elements and a body compressed as given
+    fn deep_bytes(
+        size: (u16, u16),
+        compression: u16,
+        elements: &[u16],
+        extra: &[(&[u8; 4], Vec<u8>)],
+        body: &[u8],
+    ) -> Vec<u8> {
+        let mut dgbl = Vec::new();
+        dgbl.extend_from_slice(&size.0.to_be_bytes());
+        dgbl.extend_from_slice(&size.1.to_be_bytes());
+        dgbl.extend_from_slice(&compression.to_be_bytes());
+        dgbl.extend_from_slice(&[1, 1]);
+
+        let mut dpel = (elements.len() as u32).to_be_bytes().to_vec();
+        for &kind in elements {
+            dpel.extend_from_slice(&kind.to_be_bytes());
+            dpel.extend_from_slice(&8u16.to_be_bytes());
+        }
+
+        let mut form = b"DEEP".to_vec();
+        chunk(b"DGBL", &dgbl, &mut form);
+        chunk(b"DPEL", &dpel, &mut form);
+        for (id, data) in extra {
+            chunk(id, data, &mut form);
+        }
+        chunk(b"DBOD", body, &mut form);
+
+        let mut file = Vec::new();
+        chunk(b"FORM", &form, &mut file);
+        file
+    }
+
+    /// Pixels of a 3x2 image, as RGBA
+    fn rgba_pixels() -> Vec<u8> {
+        (0..6u8)
+            .flat_map(|i| [i * 10, i * 10 + 1, i * 10 + 2, i * 40])
+            .collect()
+    }
+
+    #[test]
+    fn uncompressed() {
+        let pixels = rgba_pixels();
+        let bytes = deep_bytes((3, 2), NO_COMPRESSION, &[RED, GREEN, BLUE, ALPHA], &[], &pixels);
+
+        let image = read_test(&bytes, PixelFormat::Rgba).unwrap();
+        assert_eq!(image.pixels, pixels);
+        assert_eq!(image.planes, 32);
+        assert_eq!(image.compression, Compression::None);
+
+        let image = read_test(&bytes, PixelFormat::Rgb).unwrap();
+        let rgb: Vec<u8> = pixels.chunks(4).flat_map(|p| p[..3].to_vec()).collect();
+        assert_eq!(image.pixels, rgb);
+
+        assert!(matches!(
+            read_test(&bytes, PixelFormat::Indexed),
+            Err(IlbmError::NotSupported(_))
+        ));
+    }
+
+    #[test]
+    fn element_order() {
+        // Alpha first, and blue before red, with no alpha the result is opaque
+        let body: Vec<u8> = (0..6u8).flat_map(|i| [i, 100 + i, 50 + i]).collect();
+        let bytes = deep_bytes((2, 3), NO_COMPRESSION, &[BLUE, GREEN, RED], &[], &body);
+
+        let image = read_test(&bytes, PixelFormat::Rgba).unwrap();
+        let expected: Vec<u8> = (0..6u8).flat_map(|i| [50 + i, 100 + i, i, 255]).collect();
+        assert_eq!(image.pixels, expected);
+    }
+
+    #[test]
+    fn run_length() {
+        let pixels = rgba_pixels();
+
+        // Two literal pixels, then a run of three, crossing into the second row,
+        // a no-op, then a literal of the last one
+        let mut body = vec![1];
+        body.extend_from_slice(&pixels[..8]);
+        body.extend_from_slice(&[(-2i8) as u8, 7, 7, 7, 7, 0x80, 0]);
+        body.extend_from_slice(&pixels[20..]);
+
+        let bytes = deep_bytes((3, 2), RUN_LENGTH, &[RED, GREEN, BLUE, ALPHA], &[], &body);
+        let image = read_test(&bytes, PixelFormat::Rgba).unwrap();
+        assert_eq!(image.compression, Compression::Deep(RUN_LENGTH));
+
+        let mut expected = pixels[..8].to_vec();
+        expected.extend_from_slice(&[7; 12]);
+        expected.extend_from_slice(&pixels[20..]);
+        assert_eq!(image.pixels, expected);
+    }
+
+    #[test]
+    fn tvdc() {
+        let table: [i16; 16] = [0, 1, 2, 4, 8, 16, 32, 64, -1, -2, -4, -8, -16, -32, -64, -128];
+        let table_bytes: Vec<u8> = table.iter().flat_map(|d| d.to_be_bytes()).collect();
+
+        // Red goes up 1, 2 and 4 (3 nibbles, rounded up to 2 bytes), green is 64
+        // repeated (3 nibbles), blue is 8 then down 1 twice (3 nibbles)
+        let row = [0x12, 0x30, 0x70, 0x20, 0x48, 0x80];
+        let body: Vec<u8> = row.iter().chain(row.iter()).copied().collect();
+
+        let bytes = deep_bytes(
+            (3, 2),
+            TVDC,
+            &[RED, GREEN, BLUE],
+            &[(b"TVDC", table_bytes)],
+            &body,
+        );
+        let image = read_test(&bytes, PixelFormat::Rgb).unwrap();
+        assert_eq!(image.compression, Compression::Deep(TVDC));
+
+        let row = [1, 64, 8, 3, 64, 7, 7, 64, 6];
+        let expected: Vec<u8> = row.iter().chain(row.iter()).copied().collect();
+        assert_eq!(image.pixels, expected);
+
+        // Without a table there is no knowing what the deltas are
+        let bytes = deep_bytes((3, 2), TVDC, &[RED, GREEN, BLUE], &[], &body);
+        assert!(matches!(
+            read_test(&bytes, PixelFormat::Rgb),
+            Err(IlbmError::InvalidData(_))
+        ));
+    }
+
+    #[test]
+    fn huge() {
+        // A tiny body, for the biggest display DGBL can describe
+        let table: Vec<u8> = (0..16i16).flat_map(|d| d.to_be_bytes()).collect();
+        for compression in [NO_COMPRESSION, RUN_LENGTH, TVDC] {
+            let bytes = deep_bytes(
+                (0xffff, 0xffff),
+                compression,
+                &[RED, GREEN, BLUE, ALPHA],
+                &[(b"TVDC", table.clone())],
+                &[0x7f, 1, 2, 3, 4],
+            );
+            assert!(matches!(
+                read_test(&bytes, PixelFormat::Rgba),
+                Err(IlbmError::NoData)
+            ));
+        }
+    }
+
+    #[test]
+    fn location() {
+        // The body only covers part of the display
+        let mut dloc = Vec::new();
+        for value in [2u16, 1, 5, 6] {
+            dloc.extend_from_slice(&value.to_be_bytes());
+        }
+        let body = [1, 2, 3, 4, 5, 6];
+        let bytes = deep_bytes(
+            (320, 200),
+            NO_COMPRESSION,
+            &[RED, GREEN, BLUE],
+            &[(b"DLOC", dloc)],
+            &body,
+        );
+
+        let image = read_test(&bytes, PixelFormat::Rgb).unwrap();
+        assert_eq!(image.size.width(), 2);
+        assert_eq!(image.size.height(), 1);
+        assert_eq!(image.page_size.width(), 320);
+        assert_eq!(image.pixels, body);
+
+        let probed = probe_from_reader(std::io::Cursor::new(bytes), ReadOptions::default()).unwrap();
+        assert_eq!(probed.size.width(), 2);
+        assert_eq!(probed.planes, 24);
+        assert!(probed.pixels.is_empty());
+    }
+
+    #[test]
+    fn not_supported() {
+        for compression in [HUFFMAN, DYNAMIC_HUFFMAN, JPEG, 99] {
+            let bytes = deep_bytes((3, 2), compression, &[RED, GREEN, BLUE], &[], &[0; 18]);
+            assert!(matches!(
+                read_test(&bytes, PixelFormat::Rgb),
+                Err(IlbmError::NotSupported(_))
+            ));
+        }
+
+        // Only 8 bit elements
+        let mut bytes = deep_bytes((3, 2), NO_COMPRESSION, &[RED, GREEN, BLUE], &[], &[0; 18]);
+        let bits_offset = 12 + 8 + 8 + 8 + 4 + 2;
+        bytes[bits_offset + 1] = 5;
+        assert!(matches!(
+            read_test(&bytes, PixelFormat::Rgb),
+            Err(IlbmError::NotSupported(_))
+        ));
+    }
+}
@@ -0,0 +1,444 @@
+use crate::bytes::BigEndian;
+use crate::iff::IffChunk;
+use crate::*;
+use std::convert::TryFrom;
+
+//
+// Dynamic palettes, where the color map changes from one line to the next, so a
+// picture can show far more colors than the hardware has registers. Sliced HAM
+// stores 16 colors for every line (or every other line) in a SHAM, Dynamic HiRes and
+// Dynamic HAM do the same in a CTBL, and PCHG (from Photon Paint and others) stores
+// just the registers that change, and on which lines
+//
+
+/// PCHG compression
+const PCHG_NO_COMPRESSION: u16 = 0;
+const PCHG_HUFFMAN: u16 = 1;
+
+/// PCHG flags, only one of the 12 and 32 bit formats should be set
+const PCHGF_12BIT: u16 = 1;
+const PCHGF_32BIT: u16 = 2;
+
+/// Colors to change, each a register and its new color
+pub(crate) type Changes = Vec<(usize, RgbValue)>;
+
+/// The palette changes of an image, as found in its SHAM, CTBL or PCHG
+#[derive(Debug, Default)]
+pub(crate) enum LinePalettes {
+    #[default]
+    None,
+
+    /// A whole palette (16 colors) for each line, or each pair of lines of a laced image
+    Sham(Vec<Vec<RgbValue>>),
+
+    /// A whole palette for each line
+    Ctbl(Vec<Vec<RgbValue>>),
+
+    /// The changes on each line that has any, in order, lines can start above the image
+    Pchg(Vec<(i32, Changes)>),
+}
+
+impl LinePalettes {
+    pub(crate) fn is_none(&self) -> bool {
+        matches!(self, LinePalettes::None)
+    }
+
+    /// The changes to make to the colors before each line, they carry on to the lines after.
+    /// Laced is from the CAMG, as only a laced SHAM may have a palette for two lines
+    pub(crate) fn line_changes(&self, height: usize, laced: bool) -> Vec<Changes> {
+        let whole = |palette: &Vec<RgbValue>| palette.iter().copied().enumerate().collect();
+
+        match self {
+            LinePalettes::None => vec![Vec::new(); height],
+
+            // A laced image may have a SHAM with half as many palettes as lines, where
+            // each palette is used for two lines
+            LinePalettes::Sham(palettes) => {
+                let lines_per_palette = if laced && palettes.len() * 2 == height { 2 } else { 1 };
+                (0..height)
+                    .map(|y| palettes.get(y / lines_per_palette).map(whole).unwrap_or_default())
+                    .collect()
+            }
+
+            LinePalettes::Ctbl(palettes) => (0..height)
+                .map(|y| palettes.get(y).map(whole).unwrap_or_default())
+                .collect(),
+
+            // Changes above the image all land on the first line, those below are lost
+            LinePalettes::Pchg(lines) => {
+                let mut changes = vec![Vec::new(); height];
+                for (line, line_changes) in lines {
+                    if let Some(changes) = changes.get_mut((*line).max(0) as usize) {
+                        changes.extend_from_slice(line_changes);
+                    }
+                }
+                changes
+            }
+        }
+    }
+}
+
+/// Colors in SHAM, CTBL and small PCHG changes are Amiga 12 bit, 0x0RGB
+fn color_12(word: u16) -> RgbValue {
+    let scale = |nibble: u16| (nibble & 0xf) as u8 * 0x11;
+    RgbValue(scale(word >> 8), scale(word >> 4), scale(word))
+}
+
+/// Read whole palettes of 16 12 bit colors, until the chunk runs out
+fn read_palettes(mut buf: &[u8]) -> Result<Vec<Vec<RgbValue>>> {
+    let mut palettes = Vec::with_capacity(buf.len() / 32);
+    while buf.len() >= 32 {
+        palettes.push((0..16).map(|_| Ok(color_12(buf.get_u16()?))).collect::<Result<_>>()?);
+    }
+    Ok(palettes)
+}
+
+/// SHAM, a version word (always zero), then a palette for each line
+pub(crate) fn read_sham(chunk: IffChunk) -> Result<LinePalettes> {
+    let mut buf = chunk.data();
+
+    let version = buf.get_u16()?;
+    if version != 0 {
+        return Err(IlbmError::NotSupported(format!("SHAM version {}", version)));
+    }
+
+    Ok(LinePalettes::Sham(read_palettes(buf)?))
+}
+
+/// CTBL, simply a palette for each line
+pub(crate) fn read_ctbl(chunk: IffChunk) -> Result<LinePalettes> {
+    Ok(LinePalettes::Ctbl(read_palettes(chunk.data())?))
+}
+
+/// PCHG, a header, then a mask of the lines that change, then the changes of each of
+/// those lines, which may all be Huffman compressed
+pub(crate) fn read_pchg(chunk: IffChunk) -> Result<LinePalettes> {
+    let mut buf = chunk.data();
+
+    let compression = buf.get_u16()?;
+    let flags = buf.get_u16()?;
+    let start_line = buf.get_i16()? as i32;
+    let line_count = buf.get_u16()? as usize;
+    let _changed_lines = buf.get_u16()?;
+    let _min_reg = buf.get_u16()?;
+    let _max_reg = buf.get_u16()?;
+    let _max_changes = buf.get_u16()?;
+    let _total_changes = buf.get_u32()?;
+
+    let data = match compression {
+        PCHG_NO_COMPRESSION => buf.to_vec(),
+        PCHG_HUFFMAN => {
+            let tree_size = buf.get_u32()? as usize;
+            let original_size = buf.get_u32()? as usize;
+
+            if buf.len() < tree_size {
+                return Err(IlbmError::NoData);
+            }
+            let (mut tree_bytes, compressed) = buf.split_at(tree_size);
+            let tree = (0..tree_size / 2)
+                .map(|_| tree_bytes.get_i16())
+                .collect::<Result<Vec<_>>>()?;
+
+            unpack_huffman(compressed, &tree, original_size)?
+        }
+        other => {
+            return Err(IlbmError::NotSupported(format!(
+                "PCHG compression {}",
+                other
+            )))
+        }
+    };
+
+    let mut buf = &data[..];
+
+    // A bit for each line, the high bit first, set where the line has changes
+    let mut mask = Vec::with_capacity(line_count);
+    for _ in 0..line_count.div_ceil(32) {
+        let bits = buf.get_u32()?;
+        mask.extend((0..32).map(|bit| bits & (0x8000_0000 >> bit) != 0));
+    }
+
+    let mut lines = Vec::new();
+    for (line, _) in mask.iter().take(line_count).enumerate().filter(|(_, &set)| set) {
+        let changes = if flags & PCHGF_12BIT != 0 {
+            read_small_changes(&mut buf)?
+        } else if flags & PCHGF_32BIT != 0 {
+            read_big_changes(&mut buf)?
+        } else {
+            return Err(IlbmError::InvalidData(format!(
+                "PCHG flags 0x{:X} have neither 12 nor 32 bit colors",
+                flags
+            )));
+        };
+        lines.push((start_line + line as i32, changes));
+    }
+
+    Ok(LinePalettes::Pchg(lines))
+}
+
+/// Small changes are counts of changes to registers 0 to 15, and 16 to 31, then
+/// a word for each, the register (less 16 for the second lot) in the top 4 bits,
+/// and a 12 bit color
+fn read_small_changes(buf: &mut &[u8]) -> Result<Changes> {
+    let low_count = buf.get_u8()?;
+    let high_count = buf.get_u8()?;
+
+    let mut changes = Changes::new();
+    for (count, base) in [(low_count, 0), (high_count, 16)] {
+        for _ in 0..count {
+            let word = buf.get_u16()?;
+            changes.push((base + (word >> 12) as usize, color_12(word)));
+        }
+    }
+    Ok(changes)
+}
+
+/// Big changes are a count, then a register and 8 bit alpha, red, blue and green
+/// (in that odd order) for each
+fn read_big_changes(buf: &mut &[u8]) -> Result<Changes> {
+    let count = buf.get_u16()?;
+
+    (0..count)
+        .map(|_| {
+            let register = buf.get_u16()? as usize;
+            let _alpha = buf.get_u8()?;
+            let red = buf.get_u8()?;
+            let blue = buf.get_u8()?;
+            let green = buf.get_u8()?;
+            Ok((register, RgbValue(red, green, blue)))
+        })
+        .collect()
+}
+
+/// PCHG Huffman compression, the tree is an array of words, starting from the last.
+/// A set bit either finds a leaf (a positive word) or moves back by the (negative)
+/// byte offset it holds, a clear bit moves back a word, which may be a leaf (with
+/// 0x100 set). After a leaf, we start again from the last word
+fn unpack_huffman(data: &[u8], tree: &[i16], original_size: usize) -> Result<Vec<u8>> {
+    let root = tree.len() as isize - 1;
+    if root < 0 {
+        return Err(IlbmError::InvalidData("PCHG with an empty Huffman tree".to_string()));
+    }
+
+    let node = |p: isize| -> Result<i16> {
+        usize::try_from(p)
+            .ok()
+            .and_then(|p| tree.get(p).copied())
+            .ok_or_else(|| IlbmError::InvalidData("PCHG Huffman tree is broken".to_string()))
+    };
+
+    let mut out = Vec::with_capacity(original_size);
+    let mut bytes = data.iter();
+    let mut byte = 0u8;
+    let mut bits = 0;
+    let mut p = root;
+
+    while out.len() < original_size {
+        if bits == 0 {
+            byte = *bytes.next().ok_or(IlbmError::NoData)?;
+            bits = 8;
+        }
+
+        if byte & 0x80 != 0 {
+            let word = node(p)?;
+            if word >= 0 {
+                out.push(word as u8);
+                p = root;
+            } else {
+                p += (word / 2) as isize;
+            }
+        } else {
+            p -= 1;
+            let word = node(p)?;
+            if word > 0 && word & 0x100 != 0 {
+                out.push(word as u8);
+                p = root;
+            }
+        }
+
+        byte <<= 1;
+        bits -= 1;
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read::tests::{ilbm_bytes, insert_chunks, read_test, TestImage};
+
+    fn words(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    /// A 16 color image, every pixel color 1, or for HAM modifying blue
+    fn image_bytes(camg: u32, height: usize, chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let pixel = if camg == 0x800 { 0x1f } else { 1 };
+        let bytes = ilbm_bytes(&TestImage {
+            width: 2,
+            height,
+            planes: if camg == 0x800 { 6 } else { 4 },
+            cmap: Some(vec![0x11; 48]),
+            camg: Some(camg),
+            pixels: vec![pixel; 2 * height],
+            ..Default::default()
+        });
+        insert_chunks(bytes, chunks)
+    }
+
+    /// Palettes where color 1 of line y is a gray of y
+    fn palettes(lines: u16) -> Vec<u8> {
+        (0..lines)
+            .flat_map(|y| words(&(0..16).map(|i| if i == 1 { y * 0x111 } else { 0xf00 }).collect::<Vec<_>>()))
+            .collect()
+    }
+
+    /// The first pixel of each line
+    fn first_pixels(image: &IlbmImage) -> Vec<RgbValue> {
+        let width = image.size.width() * 3;
+        image
+            .pixels
+            .chunks(width)
+            .map(|line| RgbValue(line[0], line[1], line[2]))
+            .collect()
+    }
+
+    fn gray(value: u8) -> RgbValue {
+        RgbValue(value, value, value)
+    }
+
+    #[test]
+    fn sham() {
+        let mut data = vec![0, 0];
+        data.extend(palettes(4));
+        let bytes = image_bytes(0x800, 4, &[(b"SHAM", data.clone())]);
+        let image = read_test(&bytes, PixelFormat::Rgb).unwrap();
+
+        // HAM starts each line from the border color, which is 0xf00 on every line
+        assert_eq!(image.pixels[..6], [0xff, 0, 0xff, 0xff, 0, 0xff]);
+
+        // Normal pixels pick up the color of their line
+        let bytes = image_bytes(0, 4, &[(b"SHAM", data)]);
+        let image = read_test(&bytes, PixelFormat::Rgb).unwrap();
+        assert_eq!(first_pixels(&image), [gray(0), gray(0x11), gray(0x22), gray(0x33)]);
+
+        // Indexes can't show a palette per line
+        assert!(matches!(
+            read_test(&bytes, PixelFormat::Indexed),
+            Err(IlbmError::NotSupported(_))
+        ));
+
+        // A version we don't know
+        let bytes = image_bytes(0, 4, &[(b"SHAM", vec![0, 1])]);
+        assert!(matches!(
+            read_test(&bytes, PixelFormat::Rgb),
+            Err(IlbmError::NotSupported(_))
+        ));
+    }
+
+    #[test]
+    fn sham_laced() {
+        // Half as many palettes as lines, each is used twice
+        let mut data = vec![0, 0];
+        data.extend(palettes(2));
+        let bytes = image_bytes(0x4, 4, &[(b"SHAM", data.clone())]);
+        let image = read_test(&bytes, PixelFormat::Rgb).unwrap();
+        assert_eq!(first_pixels(&image), [gray(0), gray(0), gray(0x11), gray(0x11)]);
+
+        // But not without LACE in the CAMG, they are one a line, and run out
+        let bytes = image_bytes(0, 4, &[(b"SHAM", data)]);
+        let image = read_test(&bytes, PixelFormat::Rgb).unwrap();
+        assert_eq!(first_pixels(&image), [gray(0), gray(0x11), gray(0x11), gray(0x11)]);
+
+        // As do any other number of palettes, laced or not
+        let mut data = vec![0, 0];
+        data.extend(palettes(3));
+        let bytes = image_bytes(0x4, 4, &[(b"SHAM", data)]);
+        let image = read_test(&bytes, PixelFormat::Rgb).unwrap();
+        assert_eq!(first_pixels(&image), [gray(0), gray(0x11), gray(0x22), gray(0x22)]);
+    }
+
+    #[test]
+    fn ctbl() {
+        let bytes = image_bytes(0, 3, &[(b"CTBL", palettes(3))]);
+        let image = read_test(&bytes, PixelFormat::Rgb).unwrap();
+        assert_eq!(first_pixels(&image), [gray(0), gray(0x11), gray(0x22)]);
+    }
+
+    fn pchg_header(compression: u16, flags: u16, start: i16, lines: u16) -> Vec<u8> {
+        let mut data = words(&[compression, flags, start as u16, lines, 0, 0, 31, 0]);
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn pchg_small() {
+        // Starting a line above the image, so its first change lands on line 0,
+        // then color 1 changes on line 2 (and stays), and color 17 (unused) on line 3
+        let mut data = pchg_header(PCHG_NO_COMPRESSION, PCHGF_12BIT, -1, 5);
+        data.extend_from_slice(&0x9800_0000u32.to_be_bytes());
+        data.extend_from_slice(&[1, 0]);
+        data.extend(words(&[0x1333]));
+        data.extend_from_slice(&[1, 1]);
+        data.extend(words(&[0x1fff, 0x1f00]));
+        data.extend_from_slice(&[0, 1]);
+        data.extend(words(&[0x10f0]));
+
+        let bytes = image_bytes(0, 4, &[(b"PCHG", data)]);
+        let image = read_test(&bytes, PixelFormat::Rgb).unwrap();
+        assert_eq!(first_pixels(&image), [gray(0x33), gray(0x33), gray(0xff), gray(0xff)]);
+    }
+
+    #[test]
+    fn pchg_big() {
+        // Line 1 changes color 1 to red, alpha first and blue before green
+        let mut data = pchg_header(PCHG_NO_COMPRESSION, PCHGF_32BIT, 0, 2);
+        data.extend_from_slice(&0x4000_0000u32.to_be_bytes());
+        data.extend(words(&[1, 1]));
+        data.extend_from_slice(&[0, 200, 10, 20]);
+
+        let bytes = image_bytes(0, 2, &[(b"PCHG", data)]);
+        let image = read_test(&bytes, PixelFormat::Rgb).unwrap();
+        assert_eq!(first_pixels(&image), [gray(0x11), RgbValue(200, 20, 10)]);
+
+        // Without a color size, there is no knowing how to read the changes
+        let mut data = pchg_header(PCHG_NO_COMPRESSION, 0, 0, 2);
+        data.extend_from_slice(&0x4000_0000u32.to_be_bytes());
+        let bytes = image_bytes(0, 2, &[(b"PCHG", data)]);
+        assert!(matches!(
+            read_test(&bytes, PixelFormat::Rgb),
+            Err(IlbmError::InvalidData(_))
+        ));
+    }
+
+    #[test]
+    fn huffman() {
+        // "0" is 0x00, "10" is 0x01 and "11" is 0x40
+        let tree = [0x101, 0x40, 0x100, -4];
+        let unpacked = unpack_huffman(&[0b0101_1010], &tree, 5).unwrap();
+        assert_eq!(unpacked, [0x00, 0x01, 0x40, 0x00, 0x01]);
+
+        assert!(matches!(unpack_huffman(&[0xff], &tree, 8), Err(IlbmError::NoData)));
+        assert!(unpack_huffman(&[0xff], &[-40], 1).is_err());
+    }
+
+    #[test]
+    fn pchg_huffman() {
+        // The line mask 0x40000000, then 1 small change, of color 1 to 0x001.
+        // "0" is 0x00, "11" is 0x40, "101" is 0x10 and "100" is 0x01
+        let tree = words(&[0, 0x101, 0x10, 0x40, 0x100, (-4i16) as u16]);
+        let original = [0x40, 0x00, 0x00, 0x00, 0x01, 0x00, 0x10, 0x01];
+        let packed = [0b1100_0100, 0b0101_1000];
+
+        let mut data = pchg_header(PCHG_HUFFMAN, PCHGF_12BIT, 0, 2);
+        data.extend_from_slice(&(tree.len() as u32).to_be_bytes());
+        data.extend_from_slice(&(original.len() as u32).to_be_bytes());
+        data.extend(tree);
+        data.extend_from_slice(&packed);
+
+        let bytes = image_bytes(0, 2, &[(b"PCHG", data)]);
+        let image = read_test(&bytes, PixelFormat::Rgb).unwrap();
+        assert_eq!(first_pixels(&image), [gray(0x11), RgbValue(0, 0, 0x11)]);
+    }
+}
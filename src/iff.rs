@@ -1,8 +1,14 @@
+use crate::{IlbmError, Result};
 use std::io::Cursor;
 use std::fmt;
 use std::io::prelude::*;
+use std::io::SeekFrom;
 
-const FORM: ChunkId = ChunkId::new(b"FORM");
+/// The group chunks of IFF-85, every other chunk id is a plain data chunk
+pub const FORM: ChunkId = ChunkId::new(b"FORM");
+pub const LIST: ChunkId = ChunkId::new(b"LIST");
+pub const CAT: ChunkId = ChunkId::new(b"CAT ");
+pub const PROP: ChunkId = ChunkId::new(b"PROP");
 
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 pub struct ChunkId (
@@ -13,6 +19,11 @@ impl ChunkId {
     pub const fn new(id: &[u8;4]) -> ChunkId {
         ChunkId(*id)
     }
+
+    /// Groups contain a type, then other chunks
+    pub fn is_group(&self) -> bool {
+        *self == FORM || *self == LIST || *self == CAT || *self == PROP
+    }
 }
 
 impl fmt::Display for ChunkId {
@@ -27,86 +38,652 @@ impl Default for ChunkId {
 
 pub struct IffReader<R> {
     reader: R,
-    skip: bool
+    skip: bool,
+    offset: u64,
 }
 
 impl<R:Read> IffReader<R> {
     pub fn new(reader: R) -> IffReader<R> {
-        IffReader{reader, skip: false}
+        IffReader::with_offset(reader, 0)
+    }
+
+    /// A reader whose data starts at `offset` in some larger stream, so reported offsets are
+    /// from the start of that stream, for example, the chunks inside a FORM
+    pub fn with_offset(reader: R, offset: u64) -> IffReader<R> {
+        IffReader{reader, skip: false, offset}
+    }
+
+    /// Iterate over results instead, so a truncated chunk is reported as an
+    /// error, rather than looking just like the end of the data
+    pub fn fallible(self) -> FallibleIffReader<R> {
+        FallibleIffReader { reader: self, failed: false }
+    }
+
+    fn read_fully(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let count = read_fully(&mut self.reader, buf)?;
+        self.offset += count as u64;
+        Ok(count)
+    }
+
+    /// Read the next chunk, None is a clean end of data, at a chunk boundary
+    pub fn next_chunk(&mut self) -> Result<Option<IffChunk>> {
+        // Do we need a padding byte, due to a previous odd read
+        if self.skip {
+            // Throw away the padding byte
+            let mut dummy = [0u8; 1];
+            let offset = self.offset;
+
+            if self.read_fully(&mut dummy)? != 1 {
+                return Err(IlbmError::Truncated { part: Truncation::Padding, offset });
+            }
+            self.skip = false;
+        }
+
+        let offset = self.offset;
+        let mut header = [0u8; 8];
+
+        match self.read_fully(&mut header)? {
+            0 => return Ok(None),
+            8 => (),
+            _ => return Err(IlbmError::Truncated { part: Truncation::Header, offset }),
+        }
+
+        let ck_id = ChunkId([header[0], header[1], header[2], header[3]]);
+        let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+
+        debug!("Found Chunk {} {} at {}", ck_id, len, offset);
+
+        // Don't trust the length enough to allocate it all up front,
+        // it may be nonsense, running far past the end of the data
+        let data_offset = self.offset;
+        let mut data = Vec::new();
+        let count = (&mut self.reader).take(len as u64).read_to_end(&mut data)?;
+        self.offset += count as u64;
+
+        if count != len as usize {
+            return Err(IlbmError::Truncated { part: Truncation::Data, offset: data_offset });
+        }
+
+        // If we get an odd size, we need to skip a trailing byte,
+        // before we fetch the next chunk, so take note of that
+        self.skip = len & 1 != 0;
+
+        Ok(Some(IffChunk{ck_id, data, offset}))
+    }
+}
+
+/// Read as much of buf as we can, returning how much that was, which
+/// is only less than buf.len() at the end of the data
+fn read_fully<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut count = 0;
+    while count < buf.len() {
+        match reader.read(&mut buf[count..]) {
+            Ok(0) => break,
+            Ok(n) => count += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(count)
+}
+
+/// The part of a chunk that was cut short, by the end of the data
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Truncation {
+    Header,
+    Data,
+    Padding,
+}
+
+impl fmt::Display for Truncation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Truncation::Header => write!(f, "chunk header"),
+            Truncation::Data => write!(f, "chunk data"),
+            Truncation::Padding => write!(f, "chunk pad byte"),
+        }
     }
 }
 
+#[derive(Clone)]
 pub struct IffChunk {
     ck_id: ChunkId,
-    data: Vec<u8>
+    data: Vec<u8>,
+    offset: u64,
 }
 
 impl IffChunk {
     pub fn id(&self) -> ChunkId { self.ck_id }
     pub fn data(&self) -> &[u8] { &self.data }
+
+    /// Where the chunk (its header) starts in the data we are reading
+    pub fn offset(&self) -> u64 { self.offset }
+
     pub fn is_form(&self) -> bool { 
         self.ck_id == FORM  
     }
+    pub fn is_list(&self) -> bool { self.ck_id == LIST }
+    pub fn is_cat(&self) -> bool { self.ck_id == CAT }
+    pub fn is_prop(&self) -> bool { self.ck_id == PROP }
+
     pub fn is_form_type(&self, form_type:&[u8;4]) -> bool { 
-        self.is_form() && self.data.len() >= 4 && form_type == &self.data[..4]  
+        self.is_form() && self.group_type() == Some(ChunkId(*form_type))
+    }
+
+    /// For a group (FORM, LIST, CAT or PROP) the type of what it contains
+    pub fn group_type(&self) -> Option<ChunkId> {
+        if self.ck_id.is_group() && self.data.len() >= 4 {
+            Some(ChunkId([self.data[0], self.data[1], self.data[2], self.data[3]]))
+        } else {
+            None
+        }
     }
+
+    /// The chunks inside a group, for anything else, there are none
     pub fn sub_chunks(&self) -> IffReader<Cursor<&[u8]>> {
-         IffReader::new( Cursor::new(&self.data[4..]))
+        match self.group_type() {
+            // Skip our own header, and the group type
+            Some(_) => IffReader::with_offset(Cursor::new(&self.data[4..]), self.offset + 12),
+            None => IffReader::with_offset(Cursor::new(&[]), self.offset + 8),
+        }
     } 
 }
 
 impl fmt::Display for IffChunk {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if self.is_form() && self.data.len() >= 4 {
-            write!(f, "FORM Chunk {}, length {}", String::from_utf8_lossy(&self.data[..4]), self.data.len())
+        if let Some(group_type) = self.group_type() {
+            write!(f, "{} Chunk {}, length {}", self.ck_id, group_type, self.data.len())
         } else {
             write!(f, "{} Chunk, length {}", self.ck_id, self.data.len())
         }
     }
 }
 
+/// Iterating an IffReader simply stops at the first problem,
+/// use fallible() to find out what that problem was
 impl<R: Read> Iterator for IffReader<R> {
     type Item = IffChunk;
     fn next(&mut self) -> Option<IffChunk> {
-        // Do we need a padding byte, due to a previous odd read
-        if self.skip {
-            // Throw away the padding byte
-            let mut dummy = [0u8; 1];
-        
-            if self.reader.read_exact(&mut dummy).is_err() {
-                return None;
-            }
+        self.next_chunk().ok().flatten()
+    }
+}
+
+/// Iterates over chunks, as results, ending after the first error
+pub struct FallibleIffReader<R> {
+    reader: IffReader<R>,
+    failed: bool,
+}
+
+impl<R: Read> Iterator for FallibleIffReader<R> {
+    type Item = Result<IffChunk>;
+    fn next(&mut self) -> Option<Result<IffChunk>> {
+        if self.failed {
+            return None;
+        }
+
+        let result = self.reader.next_chunk();
+        self.failed = result.is_err();
+        result.transpose()
+    }
+}
+
+/// The id and length of a chunk, and where its data starts in the stream
+#[derive(Debug, Copy, Clone)]
+pub struct ChunkHeader {
+    pub id: ChunkId,
+    pub len: u32,
+    pub data_offset: u64,
+}
+
+impl ChunkHeader {
+    pub fn is_form(&self) -> bool {
+        self.id == FORM
+    }
+    pub fn is_list(&self) -> bool { self.id == LIST }
+    pub fn is_cat(&self) -> bool { self.id == CAT }
+    pub fn is_prop(&self) -> bool { self.id == PROP }
+
+    /// Offset of the next chunk, after this one's data, and its padding byte
+    pub fn end_offset(&self) -> u64 {
+        self.data_offset + self.len as u64 + (self.len & 1) as u64
+    }
+}
+
+/// Walks the chunks of a seekable stream, loading only the data we ask for,
+/// everything else is skipped with a seek, so large chunks (BODY) cost nothing
+pub struct IffSeeker<R> {
+    reader: R,
+}
+
+impl<R: Read + Seek> IffSeeker<R> {
+    pub fn new(reader: R) -> IffSeeker<R> {
+        IffSeeker { reader }
+    }
+
+    pub fn position(&mut self) -> Result<u64> {
+        Ok(self.reader.stream_position()?)
+    }
+
+    /// Read the next chunk header, None means we reached the end of the stream,
+    /// at a chunk boundary, part of a header is an error
+    pub fn next_header(&mut self) -> Result<Option<ChunkHeader>> {
+        let offset = self.position()?;
+        let mut header = [0u8; 8];
+
+        match read_fully(&mut self.reader, &mut header)? {
+            0 => return Ok(None),
+            8 => (),
+            _ => return Err(IlbmError::Truncated { part: Truncation::Header, offset }),
         }
 
+        let id = ChunkId([header[0], header[1], header[2], header[3]]);
+        let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let data_offset = self.position()?;
+
+        debug!("Found Chunk {} {} at {}", id, len, data_offset);
+
+        Ok(Some(ChunkHeader { id, len, data_offset }))
+    }
+
+    /// The type of a group (FORM, LIST, CAT or PROP), which is the first four bytes
+    /// of its data. Afterwards, next_header returns the first chunk inside the group
+    pub fn group_type(&mut self, header: &ChunkHeader) -> Result<ChunkId> {
         let mut id = [0u8; 4];
+        self.reader.seek(SeekFrom::Start(header.data_offset))?;
+        self.reader.read_exact(&mut id)?;
+        Ok(ChunkId(id))
+    }
 
-        if self.reader.read_exact(&mut id).is_err() {
-            return None;
+    /// Load the data of a chunk, and move on to the next one
+    pub fn read_chunk(&mut self, header: &ChunkHeader) -> Result<IffChunk> {
+        self.reader.seek(SeekFrom::Start(header.data_offset))?;
+
+        // As with IffReader, the length may be nonsense, so don't allocate it up front
+        let mut data = Vec::new();
+        let count = (&mut self.reader).take(header.len as u64).read_to_end(&mut data)?;
+
+        if count != header.len as usize {
+            return Err(IlbmError::Truncated {
+                part: Truncation::Data,
+                offset: header.data_offset,
+            });
         }
 
-        let ck_id = ChunkId(id);
+        self.skip(header)?;
+        Ok(IffChunk {
+            ck_id: header.id,
+            data,
+            offset: header.data_offset - 8,
+        })
+    }
+
+    /// Move on to the chunk after this one, without reading its data
+    pub fn skip(&mut self, header: &ChunkHeader) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(header.end_offset()))?;
+        Ok(())
+    }
+}
+
+/// Writes IFF data, keeping track of open groups (FORM, LIST and CAT) so their
+/// lengths can be filled in when they are closed, and padding odd length chunks.
+///
+/// Made with new, the contents of open groups are kept in memory until the outermost
+/// group is closed, which works for any Write. Made with new_seekable everything
+/// is written straight away, and group lengths are patched afterwards
+pub struct IffWriter<W> {
+    writer: W,
+    /// Open groups, innermost last
+    groups: Vec<OpenGroup>,
+    /// Where we are in the writer, only kept up to date when seeking
+    position: u64,
+    patch: Option<PatchLength<W>>,
+}
 
-        let mut len_bytes = [0u8; 4];
+/// Writes a group length back at the given offset
+type PatchLength<W> = fn(&mut W, u64, u32) -> std::io::Result<()>;
 
-        if self.reader.read_exact(&mut len_bytes).is_err() {
-            return None;
+struct OpenGroup {
+    /// Everything written to the group, including its header, when we can't seek
+    buffer: Vec<u8>,
+    /// Where the group header starts, when we can seek
+    start: u64,
+}
+
+impl<W: Write> IffWriter<W> {
+    pub fn new(writer: W) -> IffWriter<W> {
+        IffWriter { writer, groups: Vec::new(), position: 0, patch: None }
+    }
+
+    pub fn begin_form(&mut self, form_type: ChunkId) -> Result<()> {
+        self.begin_group(FORM, form_type)
+    }
+
+    pub fn begin_list(&mut self, list_type: ChunkId) -> Result<()> {
+        self.begin_group(LIST, list_type)
+    }
+
+    pub fn begin_cat(&mut self, cat_type: ChunkId) -> Result<()> {
+        self.begin_group(CAT, cat_type)
+    }
+
+    /// Open a group chunk, everything written until the matching end_group is inside it
+    pub fn begin_group(&mut self, id: ChunkId, group_type: ChunkId) -> Result<()> {
+        self.groups.push(OpenGroup { buffer: Vec::new(), start: self.position });
+
+        // The length is filled in when the group ends
+        self.emit(&id.0)?;
+        self.emit(&[0u8; 4])?;
+        self.emit(&group_type.0)
+    }
+
+    /// Close the innermost open group
+    pub fn end_group(&mut self) -> Result<()> {
+        let mut group = self.groups.pop().ok_or_else(|| {
+            IlbmError::InvalidData("end_group with no open group".to_string())
+        })?;
+
+        match self.patch {
+            Some(patch) => {
+                let len = chunk_length((self.position - group.start - 8) as usize)?;
+                patch(&mut self.writer, group.start + 4, len)?;
+                Ok(())
+            }
+            None => {
+                let len = chunk_length(group.buffer.len() - 8)?;
+                group.buffer[4..8].copy_from_slice(&len.to_be_bytes());
+                self.emit(&group.buffer)
+            }
         }
+    }
 
-        let len = u32::from_be_bytes(len_bytes);
+    /// Write a whole chunk, adding the pad byte if the data has an odd length
+    pub fn write_chunk(&mut self, id: ChunkId, data: &[u8]) -> Result<()> {
+        let len = chunk_length(data.len())?;
 
-        // If we get an odd size, we need to skip a trailing byte,
-        // before we fetch the next chunk, so take note of that
-        self.skip = len & 1 != 0;
+        self.emit(&id.0)?;
+        self.emit(&len.to_be_bytes())?;
+        self.emit(data)?;
 
-        debug!("Found Chunk {} {}", ck_id, len);
+        if len & 1 != 0 {
+            self.emit(&[0u8])?;
+        }
+        Ok(())
+    }
 
-        let mut data = vec![0u8; len as usize];
+    /// Check every group was closed, and hand back the writer
+    pub fn finish(mut self) -> Result<W> {
+        if !self.groups.is_empty() {
+            return Err(IlbmError::InvalidData(format!(
+                "finished IFF data with {} group(s) still open",
+                self.groups.len()
+            )));
+        }
 
-        if self.reader.read_exact(&mut data).is_err() {
-            return None;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    /// Bytes go into the innermost group, if we are buffering, otherwise straight out
+    fn emit(&mut self, bytes: &[u8]) -> Result<()> {
+        match self.groups.last_mut() {
+            Some(group) if self.patch.is_none() => group.buffer.extend_from_slice(bytes),
+            _ => {
+                self.writer.write_all(bytes)?;
+                self.position += bytes.len() as u64;
+            }
         }
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> IffWriter<W> {
+    pub fn new_seekable(mut writer: W) -> Result<IffWriter<W>> {
+        let position = writer.stream_position()?;
+        Ok(IffWriter { writer, groups: Vec::new(), position, patch: Some(patch_length::<W>) })
+    }
+}
+
+fn patch_length<W: Write + Seek>(writer: &mut W, offset: u64, len: u32) -> std::io::Result<()> {
+    let end = writer.stream_position()?;
+    writer.seek(SeekFrom::Start(offset))?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
 
-        Some(IffChunk{ck_id, data})
+fn chunk_length(len: usize) -> Result<u32> {
+    if len > u32::MAX as usize {
+        Err(IlbmError::InvalidData(format!("chunk of {} bytes is too big for IFF", len)))
+    } else {
+        Ok(len as u32)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend_from_slice(data);
+        if data.len() & 1 != 0 {
+            out.push(0);
+        }
+        out
+    }
+
+    fn fallible(bytes: &[u8]) -> Vec<Result<IffChunk>> {
+        IffReader::new(Cursor::new(bytes)).fallible().collect()
+    }
+
+    fn truncation(result: &Result<IffChunk>) -> (Truncation, u64) {
+        match result {
+            Err(IlbmError::Truncated { part, offset }) => (*part, *offset),
+            Err(e) => panic!("expected truncation, got {}", e),
+            Ok(chunk) => panic!("expected truncation, got {}", chunk),
+        }
+    }
+
+    #[test]
+    fn clean_end() {
+        let mut bytes = chunk(b"ABCD", &[1, 2, 3]);
+        bytes.extend(chunk(b"EFGH", &[4, 5]));
+
+        let chunks = fallible(&bytes);
+        assert_eq!(chunks.len(), 2);
+
+        let first = chunks[0].as_ref().unwrap();
+        assert_eq!(first.id(), ChunkId::new(b"ABCD"));
+        assert_eq!(first.data(), &[1, 2, 3]);
+        assert_eq!(first.offset(), 0);
+
+        let second = chunks[1].as_ref().unwrap();
+        assert_eq!(second.data(), &[4, 5]);
+        assert_eq!(second.offset(), 12);
+
+        assert!(fallible(&[]).is_empty());
+    }
+
+    #[test]
+    fn truncated_header() {
+        let mut bytes = chunk(b"ABCD", &[1, 2]);
+        bytes.extend_from_slice(b"EFG");
+
+        let chunks = fallible(&bytes);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(truncation(&chunks[1]), (Truncation::Header, 10));
+    }
+
+    #[test]
+    fn truncated_data() {
+        // Claims far more data than there is
+        let mut bytes = chunk(b"ABCD", &[1, 2]);
+        bytes[4..8].copy_from_slice(&0xffff_fff0u32.to_be_bytes());
+
+        let chunks = fallible(&bytes);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(truncation(&chunks[0]), (Truncation::Data, 8));
+    }
+
+    #[test]
+    fn missing_padding() {
+        let mut bytes = chunk(b"ABCD", &[1, 2, 3]);
+        bytes.pop();
+
+        let chunks = fallible(&bytes);
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].is_ok());
+        assert_eq!(truncation(&chunks[1]), (Truncation::Padding, 11));
+    }
+
+    #[test]
+    fn sub_chunk_offsets() {
+        let mut form_data = b"TEST".to_vec();
+        form_data.extend(chunk(b"ABCD", &[1]));
+        form_data.extend(chunk(b"EFGH", &[2, 3, 4, 5]));
+        form_data.truncate(form_data.len() - 1);
+
+        let mut bytes = chunk(b"JUNK", &[0; 6]);
+        bytes.extend(chunk(b"FORM", &form_data));
+
+        let form = IffReader::new(Cursor::new(&bytes)).nth(1).unwrap();
+        assert!(form.is_form_type(b"TEST"));
+        assert_eq!(form.offset(), 14);
+
+        let subs: Vec<Result<IffChunk>> = form.sub_chunks().fallible().collect();
+        assert_eq!(subs[0].as_ref().unwrap().offset(), 26);
+        assert_eq!(truncation(&subs[1]), (Truncation::Data, 44));
+
+        // The plain iterator just stops
+        assert_eq!(form.sub_chunks().count(), 1);
+    }
+
+    #[test]
+    fn seeker_truncated_header() {
+        let mut bytes = chunk(b"ABCD", &[1, 2]);
+        bytes.extend_from_slice(b"EFG");
+
+        let mut seeker = IffSeeker::new(Cursor::new(&bytes));
+        let header = seeker.next_header().unwrap().unwrap();
+        seeker.skip(&header).unwrap();
+
+        match seeker.next_header() {
+            Err(IlbmError::Truncated { part: Truncation::Header, offset: 10 }) => (),
+            other => panic!("expected a truncated header, got {:?}", other.map(|h| h.map(|h| h.id))),
+        }
+
+        // But a clean end is fine
+        let mut seeker = IffSeeker::new(Cursor::new(&bytes[..10]));
+        let header = seeker.next_header().unwrap().unwrap();
+        seeker.skip(&header).unwrap();
+        assert!(seeker.next_header().unwrap().is_none());
+    }
+
+    #[test]
+    fn seeker_truncated_data() {
+        // Claims far more data than there is, which we must not try to allocate
+        let mut bytes = chunk(b"ABCD", &[1, 2]);
+        bytes[4..8].copy_from_slice(&0xffff_fff0u32.to_be_bytes());
+
+        let mut seeker = IffSeeker::new(Cursor::new(&bytes));
+        let header = seeker.next_header().unwrap().unwrap();
+
+        match seeker.read_chunk(&header) {
+            Err(IlbmError::Truncated { part: Truncation::Data, offset: 8 }) => (),
+            other => panic!("expected truncated data, got {:?}", other.map(|c| c.to_string())),
+        }
+    }
+
+    /// A FORM inside a LIST inside a CAT, with odd length chunks along the way
+    fn write_nested<W: Write>(iff: &mut IffWriter<W>) -> Result<()> {
+        iff.begin_cat(ChunkId::new(b"TEST"))?;
+        iff.write_chunk(ChunkId::new(b"ABCD"), &[1, 2, 3])?;
+        iff.begin_list(ChunkId::new(b"TEST"))?;
+        iff.begin_form(ChunkId::new(b"TEST"))?;
+        iff.write_chunk(ChunkId::new(b"EFGH"), &[4])?;
+        iff.write_chunk(ChunkId::new(b"IJKL"), &[])?;
+        iff.end_group()?;
+        iff.end_group()?;
+        iff.end_group()
+    }
+
+    fn check_nested(bytes: &[u8]) {
+        let cat = IffReader::new(Cursor::new(bytes)).fallible().next().unwrap().unwrap();
+        assert_eq!(cat.id(), CAT);
+        assert_eq!(cat.data().len() + 8, bytes.len());
+        assert_eq!(&cat.data()[..4], b"TEST");
+
+        let subs: Vec<IffChunk> = cat.sub_chunks().fallible().map(|c| c.unwrap()).collect();
+        assert_eq!(subs.len(), 2);
+        assert_eq!(subs[0].data(), &[1, 2, 3]);
+        assert_eq!(subs[1].id(), LIST);
+
+        let form = subs[1].sub_chunks().fallible().next().unwrap().unwrap();
+        assert!(form.is_form_type(b"TEST"));
+
+        let chunks: Vec<IffChunk> = form.sub_chunks().fallible().map(|c| c.unwrap()).collect();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].id(), ChunkId::new(b"EFGH"));
+        assert_eq!(chunks[0].data(), &[4]);
+        assert_eq!(chunks[1].data().len(), 0);
+    }
+
+    #[test]
+    fn write_buffered() {
+        let mut iff = IffWriter::new(Vec::new());
+        write_nested(&mut iff).unwrap();
+        let bytes = iff.finish().unwrap();
+        check_nested(&bytes);
+    }
+
+    #[test]
+    fn write_seekable() {
+        // Start part way into the stream, to be sure offsets are relative to where we began
+        let mut cursor = Cursor::new(vec![0xaa; 3]);
+        cursor.set_position(3);
+
+        let mut iff = IffWriter::new_seekable(cursor).unwrap();
+        write_nested(&mut iff).unwrap();
+        let bytes = iff.finish().unwrap().into_inner();
+
+        check_nested(&bytes[3..]);
+
+        let mut buffered = IffWriter::new(Vec::new());
+        write_nested(&mut buffered).unwrap();
+        assert_eq!(&bytes[3..], &buffered.finish().unwrap()[..]);
+    }
+
+    #[test]
+    fn write_unbalanced() {
+        let mut iff = IffWriter::new(Vec::new());
+        assert!(iff.end_group().is_err());
+
+        iff.begin_form(ChunkId::new(b"TEST")).unwrap();
+        assert!(iff.finish().is_err());
+    }
+
+    #[test]
+    fn groups() {
+        let mut iff = IffWriter::new(Vec::new());
+        iff.begin_list(ChunkId::new(b"TEST")).unwrap();
+        iff.begin_group(PROP, ChunkId::new(b"TEST")).unwrap();
+        iff.write_chunk(ChunkId::new(b"ABCD"), &[1]).unwrap();
+        iff.end_group().unwrap();
+        iff.end_group().unwrap();
+        let bytes = iff.finish().unwrap();
+
+        let list = IffReader::new(Cursor::new(&bytes)).next().unwrap();
+        assert!(list.is_list());
+        assert_eq!(list.group_type(), Some(ChunkId::new(b"TEST")));
+        assert!(!list.is_form_type(b"TEST"));
+
+        let prop = list.sub_chunks().next().unwrap();
+        assert!(prop.is_prop());
+        assert_eq!(prop.to_string(), "PROP Chunk TEST, length 14");
+
+        let data = prop.sub_chunks().next().unwrap();
+        assert_eq!(data.group_type(), None);
+        assert_eq!(data.sub_chunks().count(), 0);
+    }
+}
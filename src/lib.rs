@@ -3,16 +3,56 @@ extern crate log;
 pub mod iff;
 mod bytes;
 mod compression;
+mod cycle;
+mod deep;
+mod dynamic;
+mod mode;
 mod read;
+mod write;
 
+pub use compression::Packer;
+pub use cycle::{ColorCycle, CycleFrame, CycleKind};
+pub use mode::{DisplayMode, Monitor};
 use iff::ChunkId;
 use thiserror::Error;
+use std::io::{Read, Seek, Write};
 use std::path::Path;
 
 /// Global settings when reading image files
 pub struct ReadOptions {
     pub read_pixels: bool,
     pub page_scale: bool,
+    pub pixel_format: PixelFormat,
+    pub halfbrite: HalfbritePolicy,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            read_pixels: true,
+            page_scale: false,
+            pixel_format: PixelFormat::default(),
+            halfbrite: HalfbritePolicy::default(),
+        }
+    }
+}
+
+/// Where the darker upper half of the color map comes from, for Extra-HalfBrite
+/// images. Many store 64 colors, but writers disagree on what goes in the upper 32
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum HalfbritePolicy {
+    /// Always half the lower 32 colors, as the hardware does, ignoring any stored upper half
+    #[default]
+    Derive,
+    /// Use the upper half of the CMAP where it has one, otherwise derive it
+    TrustStored,
+}
+
+/// Global settings when writing image files
+#[derive(Default)]
+pub struct WriteOptions {
+    /// How to pack rows, if the image is to be compressed
+    pub packer: Packer,
 }
 
 /// Main entry point
@@ -20,6 +60,55 @@ pub fn read_from_file<P: AsRef<Path>>(file: P, options: ReadOptions) -> Result<I
     read::read_file(file, options)
 }
 
+/// Read an image already in memory, for example from an archive
+pub fn read_from_bytes(bytes: &[u8], options: ReadOptions) -> Result<IlbmImage> {
+    read::read_bytes(bytes, options)
+}
+
+/// Read an image from anything that implements Read, the whole stream is read into memory
+pub fn read_from_reader<R: Read>(reader: R, options: ReadOptions) -> Result<IlbmImage> {
+    read::read_reader(reader, options)
+}
+
+/// Read every image in a file, for files that hold more than one, such as a
+/// CAT or LIST of brushes. Each comes with its position, and its own result,
+/// so one bad image does not hide the others
+pub fn read_all_from_file<P: AsRef<Path>>(file: P, options: ReadOptions) -> Result<FoundImages> {
+    read::read_all_file(file, options)
+}
+
+/// Read every image, from data already in memory
+pub fn read_all_from_bytes(bytes: &[u8], options: ReadOptions) -> Result<FoundImages> {
+    read::read_all_bytes(bytes, options)
+}
+
+/// Read every image, from anything that implements Read
+pub fn read_all_from_reader<R: Read>(reader: R, options: ReadOptions) -> Result<FoundImages> {
+    read::read_all_reader(reader, options)
+}
+
+/// Read just the header information of an image, leaving pixels empty.
+/// Only the header chunks are read, the BODY is skipped, which is much faster than
+/// read_from_file with read_pixels false, for large images
+pub fn probe_from_file<P: AsRef<Path>>(file: P, options: ReadOptions) -> Result<IlbmImage> {
+    read::probe_file(file, options)
+}
+
+/// Read just the header information of an image, from any seekable stream
+pub fn probe_from_reader<R: Read + Seek>(reader: R, options: ReadOptions) -> Result<IlbmImage> {
+    read::probe_reader(reader, options)
+}
+
+/// Write an image to a file, as FORM ILBM, the BODY is compressed (with ByteRun1) if image.compression is set
+pub fn write_to_file<P: AsRef<Path>>(file: P, image: &IlbmImage, options: WriteOptions) -> Result<()> {
+    write::write_file(file, image, options)
+}
+
+/// Write an image, as FORM ILBM, to anything that implements Write
+pub fn write_to<W: Write>(writer: W, image: &IlbmImage, options: WriteOptions) -> Result<()> {
+    write::write(writer, image, options)
+}
+
 /// Custom errors for ilbm library
 #[derive(Error, Debug)]
 pub enum IlbmError {
@@ -52,6 +141,9 @@ pub enum IlbmError {
     #[error("{0} not supported")]
     NotSupported(String),
 
+    #[error("{part} truncated at offset {offset}")]
+    Truncated { part: iff::Truncation, offset: u64 },
+
     #[error("IO Error")]
     Io {
         #[from]
@@ -62,16 +154,70 @@ pub enum IlbmError {
 /// Standardize my result Errors
 pub type Result<T> = std::result::Result<T,IlbmError>;
 
-#[derive(Debug,Clone,Copy, PartialEq)]
+#[derive(Debug,Clone,Copy, PartialEq, Default)]
 pub enum Masking {
+    #[default]
     NoMask, 
     HasMask,
     HasTransparentColor,
     Lasso
 }
 
-impl Default for Masking {
-    fn default() -> Self { Masking::NoMask }
+/// How decoded pixels are laid out in `IlbmImage::pixels`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PixelFormat {
+    /// Three bytes per pixel, red, green and blue
+    #[default]
+    Rgb,
+    /// Four bytes per pixel, red, green, blue and alpha.
+    /// Alpha is 0 where the image is transparent, 255 where it is opaque
+    Rgba,
+    /// One byte per pixel, an index into the image color map.
+    /// Only possible for images with a color map, and not HAM
+    Indexed,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Rgb => 3,
+            PixelFormat::Rgba => 4,
+            PixelFormat::Indexed => 1,
+        }
+    }
+}
+
+/// How the BODY is compressed, from the BMHD
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Runs of bytes along each row, by far the most common
+    ByteRun1,
+    /// Runs of words down each column, one VDAT chunk per plane, from Atari ST DeluxePaint
+    ByteRun2,
+    /// Runs of whole pixels, as used by RGBN and RGB8
+    Impulse,
+    /// One of the DEEP schemes, as numbered in its DGBL (1 is run length, 5 is TVDC)
+    Deep(u16),
+    /// A BMHD value we don't know, the header is still readable, but not the BODY
+    NotSupported(u8),
+}
+
+impl Compression {
+    pub fn is_compressed(&self) -> bool {
+        *self != Compression::None
+    }
+}
+
+fn as_compression(v: u8) -> Compression {
+    match v {
+        0 => Compression::None,
+        1 => Compression::ByteRun1,
+        2 => Compression::ByteRun2,
+        4 => Compression::Impulse,
+        x => Compression::NotSupported(x),
+    }
 }
 
 fn as_masking(v: u8) -> Masking {
@@ -87,40 +233,19 @@ fn as_masking(v: u8) -> Masking {
     }
 }
 
-/// Display mode, aka ModeID is Amiga specific, and quite complex
-/// in terms of interpretation. However, our usage is pretty trivial
-// It comes from the CAMG chunk 
-#[derive(Copy, Debug, Clone, Default)]
-pub struct DisplayMode (u32);
-
-impl DisplayMode {
-    pub fn is_ham(&self) -> bool {self.0 & 0x800 != 0} 
-    pub fn is_halfbrite(&self) -> bool {self.0 & 0x80 != 0}
+#[derive(Copy, Debug, Clone, Default, PartialEq)]
+pub struct RgbValue (u8, u8, u8);
 
-    pub fn new(mode: u32) -> DisplayMode {
-        DisplayMode(mode)
-    }
-    
-    pub fn ham() -> DisplayMode {
-        DisplayMode(0x800)
+impl RgbValue {
+    pub fn new(red: u8, green: u8, blue: u8) -> RgbValue {
+        RgbValue(red, green, blue)
     }
-}
 
-impl std::fmt::Display for DisplayMode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
-        if self.is_ham() { 
-            write!(f, "0x{:X} HAM", self.0)
-        } else if self.is_halfbrite() { 
-            write!(f, "0x{:X} HALF", self.0) 
-        } else {
-            write!(f, "0x{:X}", self.0)
-        }
-    }
+    pub fn red(&self) -> u8 {self.0}
+    pub fn green(&self) -> u8 {self.1}
+    pub fn blue(&self) -> u8 {self.2}
 }
 
-#[derive(Copy, Debug, Clone, Default)]
-pub struct RgbValue (u8, u8, u8);
-
 /// This is an amalgam of information drawn from
 /// various chunks in the ILBM, mapped to more native
 /// types such as usize for u16, and enums for masking
@@ -130,23 +255,63 @@ pub struct IlbmImage {
     pub map_size: usize,
     pub planes: usize,
     pub masking: Masking,
-    pub compression: bool,
+    pub compression: Compression,
     pub display_mode: DisplayMode,
     pub dpi: Size2D,
     pub pixel_aspect: Size2D,
     pub transparent_color: usize, // Actually a color index
     pub page_size: Size2D,
 
-    /// RGB data triples
+    /// The color map (CMAP), if the image has one. For halfbrite
+    /// images read as Indexed this includes the darkened upper half
+    pub color_map: Option<ColorMap>,
+
+    /// Ranges of the color map to cycle, from CRNG, CCRT and DRNG chunks, in the order found
+    pub color_cycles: Vec<ColorCycle>,
+
+    /// Layout of the data in pixels, taken from the ReadOptions
+    pub pixel_format: PixelFormat,
+
+    /// RGB data triples, RGBA quads, or color map indexes, depending on pixel_format
     /// Left to right in row, then top to bottom
-    /// so indexes look like y * width + x where
+    /// so indexes look like (y * width + x) * bytes_per_pixel where
     /// y=0 is the top  
     pub pixels: Vec<u8>
 }
 
+impl IlbmImage {
+    /// The colors of the color map, after cycling for the given time, so
+    /// an Indexed image can be shown as it would be animated
+    pub fn palette_at(&self, time: std::time::Duration) -> Option<Vec<RgbValue>> {
+        let color_map = self.color_map.as_ref()?;
+        Some(cycle::colors_at(&color_map.colors, &self.color_cycles, time))
+    }
+
+    /// The shortest time after which every color cycle is back where it started, so
+    /// frames for this long loop smoothly. None if nothing cycles, or it would take
+    /// too long to count
+    pub fn cycle_loop(&self) -> Option<std::time::Duration> {
+        let map_size = self.color_map.as_ref().map_or(0, |map| map.len());
+        cycle::loop_ticks(&self.color_cycles, map_size).map(cycle::ticks_to_duration)
+    }
+
+    /// The frames of an Indexed image, animated by color cycling for the given time,
+    /// at Amiga timing, each is the same pixels with a new palette
+    pub fn cycle_frames(&self, duration: std::time::Duration) -> Result<Vec<CycleFrame<'_>>> {
+        cycle::frames(self, duration)
+    }
+}
+
 impl std::fmt::Display for IlbmImage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
-        let compressed = if self.compression { "Comp" } else { "" };
+        let compressed = match self.compression {
+            Compression::None => "",
+            Compression::ByteRun1 | Compression::Impulse => "Comp",
+            Compression::ByteRun2 => "VDAT",
+            Compression::Deep(5) => "TVDC",
+            Compression::Deep(_) => "Comp",
+            Compression::NotSupported(_) => "Unknown",
+        };
         write!(f, "{} dpi:{} p:{} {} {:?} map:{} mode:{} aspect:{} trans:{} page:{}",
         self.size, self.dpi, self.planes,
         compressed, self.masking, self.map_size, self.display_mode, 
@@ -154,10 +319,32 @@ impl std::fmt::Display for IlbmImage {
     }
 }
 
+/// Where an image was found, in a file that may hold several
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ImagePosition {
+    /// Counting from zero, in the order the images appear
+    pub index: usize,
+    /// Byte offset of the image FORM, from the start of the file
+    pub offset: u64,
+}
+
+impl std::fmt::Display for ImagePosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        write!(f, "#{} at {}", self.index, self.offset)
+    }
+}
+
+/// Every image in a file, with where it was found
+pub type FoundImages = Vec<(ImagePosition, Result<IlbmImage>)>;
+
 #[derive(Debug, Copy, Clone, Default)]
 pub struct Size2D (usize,usize);
 
 impl Size2D {
+    pub fn new(width: usize, height: usize) -> Size2D {
+        Size2D(width, height)
+    }
+
     pub fn width(&self) -> usize {self.0}
     pub fn height(&self) -> usize {self.1}
 }
@@ -168,7 +355,34 @@ impl std::fmt::Display for Size2D {
     }
 }
 
+/// The palette of an image, from the CMAP chunk
 #[derive(Debug, Clone)]
-struct ColorMap {
-    colors: Vec<RgbValue>
+pub struct ColorMap {
+    colors: Vec<RgbValue>,
+    original: Vec<RgbValue>,
+    fixed_up: bool,
+}
+
+impl ColorMap {
+    /// A map of exactly these colors, for building an image to write
+    pub fn new(colors: Vec<RgbValue>) -> ColorMap {
+        ColorMap {
+            original: colors.clone(),
+            colors,
+            fixed_up: false,
+        }
+    }
+
+    /// The colors used to render the image
+    pub fn colors(&self) -> &[RgbValue] {&self.colors}
+
+    /// The colors exactly as stored in the CMAP chunk, before any fix up
+    pub fn original_colors(&self) -> &[RgbValue] {&self.original}
+
+    /// True when this looked like an old style map, using only the top four bits
+    /// of each component, so the colors were scaled up to reach full brightness
+    pub fn is_fixed_up(&self) -> bool {self.fixed_up}
+
+    pub fn len(&self) -> usize {self.colors.len()}
+    pub fn is_empty(&self) -> bool {self.colors.is_empty()}
 }
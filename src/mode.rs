@@ -0,0 +1,269 @@
+//
+// Amiga display modes, as stored in CAMG. Before 2.0 this was just the 16 bit
+// viewport modes, from then on the upper word is a monitor ID, and the whole thing
+// is a ModeID. Plenty of writers left junk in the upper word though, or set bits
+// that only mean something to the copper, so it pays to clean a mode before using it
+//
+
+/// Legacy viewport mode bits
+const LACE: u32 = 0x4;
+const SUPERHIRES: u32 = 0x20;
+pub(crate) const EXTRA_HALFBRITE: u32 = 0x80;
+const DUALPF: u32 = 0x400;
+pub(crate) const HAM: u32 = 0x800;
+const EXTENDED_MODE: u32 = 0x1000;
+const HIRES: u32 = 0x8000;
+
+/// Bits that say nothing about the image, and have no place in a ModeID
+const BADFLAGS: u32 = 0x4000 | 0x2000 | 0x100 | 0x2;
+
+/// The monitor part of a ModeID, EXTENDED_MODE is included because all monitor IDs set it
+const MONITOR_ID_MASK: u32 = 0xFFFF_1000;
+
+/// Which monitor a mode is meant for, from the upper word of a ModeID
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Monitor {
+    /// No monitor ID, whatever the machine was set up with (a legacy mode)
+    Default,
+    Ntsc,
+    Pal,
+    /// VGA, aka Multiscan (productivity modes)
+    Multiscan,
+    A2024,
+    Proto,
+    Euro72,
+    Euro36,
+    Super72,
+    DblNtsc,
+    DblPal,
+    /// A monitor ID we don't know, holding the masked ID
+    Unknown(u32),
+}
+
+impl Monitor {
+    fn from_mode(mode: u32) -> Monitor {
+        match mode & MONITOR_ID_MASK {
+            0 => Monitor::Default,
+            0x1_1000 => Monitor::Ntsc,
+            0x2_1000 => Monitor::Pal,
+            0x3_1000 => Monitor::Multiscan,
+            0x4_1000 => Monitor::A2024,
+            0x5_1000 => Monitor::Proto,
+            0x6_1000 => Monitor::Euro72,
+            0x7_1000 => Monitor::Euro36,
+            0x8_1000 => Monitor::Super72,
+            0x9_1000 => Monitor::DblNtsc,
+            0xA_1000 => Monitor::DblPal,
+            id => Monitor::Unknown(id),
+        }
+    }
+
+    /// The name the display database uses, None for the default monitor
+    pub fn name(&self) -> Option<String> {
+        let name = match self {
+            Monitor::Default => return None,
+            Monitor::Ntsc => "NTSC",
+            Monitor::Pal => "PAL",
+            Monitor::Multiscan => "MULTISCAN",
+            Monitor::A2024 => "A2024",
+            Monitor::Proto => "PROTO",
+            Monitor::Euro72 => "EURO72",
+            Monitor::Euro36 => "EURO36",
+            Monitor::Super72 => "SUPER72",
+            Monitor::DblNtsc => "DBLNTSC",
+            Monitor::DblPal => "DBLPAL",
+            Monitor::Unknown(id) => return Some(format!("0x{:X}", id)),
+        };
+        Some(name.to_string())
+    }
+}
+
+/// Display mode, aka ModeID is Amiga specific, and quite complex
+/// in terms of interpretation. It comes from the CAMG chunk, the
+/// accessors all work on the cleaned up mode
+#[derive(Copy, Debug, Clone, Default)]
+pub struct DisplayMode(u32);
+
+impl DisplayMode {
+    pub fn new(mode: u32) -> DisplayMode {
+        DisplayMode(mode)
+    }
+
+    pub fn ham() -> DisplayMode {
+        DisplayMode(HAM)
+    }
+
+    /// The mode as it was stored
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+
+    /// The mode with anything meaningless removed, as the ILBM reference code (getcamg)
+    /// does. A legacy mode loses the flags that only the copper cares about, and an upper
+    /// word without EXTENDED_MODE is junk from an old writer (DPaint II brushes, for one),
+    /// so none of it can be trusted, not even HAM or halfbrite, and the mode is zero
+    pub fn cleaned(&self) -> DisplayMode {
+        let mode = self.0;
+
+        if mode & 0xFFFF_0000 == 0 {
+            DisplayMode(mode & !(EXTENDED_MODE | BADFLAGS))
+        } else if self.is_bogus() {
+            DisplayMode(0)
+        } else {
+            DisplayMode(mode)
+        }
+    }
+
+    /// Junk in the upper word, without EXTENDED_MODE, so the mode can't be used at all,
+    /// a reader should carry on as if there were no CAMG
+    pub fn is_bogus(&self) -> bool {
+        self.0 & 0xFFFF_0000 != 0 && self.0 & EXTENDED_MODE == 0
+    }
+
+    /// The cleaned mode as the hardware would show it, halfbrite does nothing in HAM mode,
+    /// so it is dropped, leaving the stored mode as it was
+    pub fn effective(&self) -> DisplayMode {
+        let mode = self.cleaned().0;
+        if mode & HAM != 0 {
+            DisplayMode(mode & !EXTRA_HALFBRITE)
+        } else {
+            DisplayMode(mode)
+        }
+    }
+
+    pub fn monitor(&self) -> Monitor {
+        Monitor::from_mode(self.cleaned().0)
+    }
+
+    pub fn is_ham(&self) -> bool {
+        self.cleaned().0 & HAM != 0
+    }
+
+    pub fn is_halfbrite(&self) -> bool {
+        self.cleaned().0 & EXTRA_HALFBRITE != 0
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.cleaned().0 & HIRES != 0 && !self.is_superhires()
+    }
+
+    pub fn is_superhires(&self) -> bool {
+        self.cleaned().0 & SUPERHIRES != 0
+    }
+
+    pub fn is_laced(&self) -> bool {
+        self.cleaned().0 & LACE != 0
+    }
+
+    pub fn is_dual_playfield(&self) -> bool {
+        self.cleaned().0 & DUALPF != 0
+    }
+
+    /// Something like the display database name, e.g. "PAL:HighRes Laced"
+    pub fn name(&self) -> String {
+        let resolution = if self.is_superhires() {
+            "SuperHighRes"
+        } else if self.is_hires() {
+            "HighRes"
+        } else {
+            "LowRes"
+        };
+
+        let mut name = match self.monitor().name() {
+            Some(monitor) => format!("{}:{}", monitor, resolution),
+            None => resolution.to_string(),
+        };
+
+        if self.is_laced() {
+            name.push_str(" Laced");
+        }
+        if self.is_dual_playfield() {
+            name.push_str(" DualPF");
+        }
+        if self.is_ham() {
+            name.push_str(" HAM");
+        }
+        if self.is_halfbrite() {
+            name.push_str(" EHB");
+        }
+
+        name
+    }
+}
+
+impl std::fmt::Display for DisplayMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        write!(f, "0x{:X} {}", self.0, self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_modes() {
+        let mode = DisplayMode::new(0x8004);
+        assert!(mode.is_hires());
+        assert!(mode.is_laced());
+        assert!(!mode.is_superhires());
+        assert_eq!(mode.monitor(), Monitor::Default);
+        assert_eq!(mode.name(), "HighRes Laced");
+
+        assert_eq!(DisplayMode::new(0).name(), "LowRes");
+        assert_eq!(DisplayMode::ham().name(), "LowRes HAM");
+        assert_eq!(DisplayMode::new(0x80).name(), "LowRes EHB");
+        assert_eq!(DisplayMode::new(0x8400).name(), "HighRes DualPF");
+        assert_eq!(DisplayMode::new(0x8024).name(), "SuperHighRes Laced");
+    }
+
+    #[test]
+    fn monitor_modes() {
+        let mode = DisplayMode::new(0x2_9004);
+        assert_eq!(mode.monitor(), Monitor::Pal);
+        assert_eq!(mode.name(), "PAL:HighRes Laced");
+        assert_eq!(mode.to_string(), "0x29004 PAL:HighRes Laced");
+
+        assert_eq!(DisplayMode::new(0x1_1800).name(), "NTSC:LowRes HAM");
+        assert_eq!(DisplayMode::new(0xA_1000).name(), "DBLPAL:LowRes");
+        assert_eq!(DisplayMode::new(0x3_9025).monitor(), Monitor::Multiscan);
+        assert_eq!(DisplayMode::new(0x4_1000).monitor(), Monitor::A2024);
+        assert_eq!(DisplayMode::new(0x1F_1000).monitor(), Monitor::Unknown(0x1F_1000));
+        assert_eq!(DisplayMode::new(0x1F_1000).name(), "0x1F1000:LowRes");
+    }
+
+    #[test]
+    fn cleaned_modes() {
+        // Junk in the upper word, with no EXTENDED_MODE, makes the whole mode junk,
+        // so the HAM bit in the lower word is not believed
+        let mode = DisplayMode::new(0xDEAD_8804);
+        assert_eq!(mode.cleaned().value(), 0);
+        assert_eq!(mode.value(), 0xDEAD_8804);
+        assert_eq!(mode.monitor(), Monitor::Default);
+        assert!(!mode.is_ham());
+        assert!(mode.is_bogus());
+        assert!(!DisplayMode::new(0x0001_0080).is_halfbrite());
+        assert!(!DisplayMode::new(0x2_1800).is_bogus());
+        assert!(!DisplayMode::new(0x8804).is_bogus());
+        assert_eq!(mode.name(), "LowRes");
+
+        // Sprites, VP_HIDE and genlock bits go from legacy modes
+        assert_eq!(DisplayMode::new(0x6106).cleaned().value(), 0x4);
+        assert_eq!(DisplayMode::new(0x1080).cleaned().value(), 0x80);
+
+        // But a real ModeID is left alone
+        assert_eq!(DisplayMode::new(0x2_1800).cleaned().value(), 0x2_1800);
+    }
+
+    #[test]
+    fn effective_modes() {
+        let mode = DisplayMode::new(0x2_1880);
+        assert!(mode.is_halfbrite());
+        assert!(!mode.effective().is_halfbrite());
+        assert!(mode.effective().is_ham());
+        assert_eq!(mode.value(), 0x2_1880);
+
+        assert!(DisplayMode::new(0x80).effective().is_halfbrite());
+        assert_eq!(DisplayMode::new(0x6084).effective().value(), 0x84);
+    }
+}
@@ -1,7 +1,11 @@
 use crate::bytes::BigEndian;
 use crate::compression;
-use crate::iff::{IffChunk, IffReader};
+use crate::cycle;
+use crate::deep::{self, DeepHeader};
+use crate::dynamic::{self, LinePalettes};
+use crate::iff::{ChunkHeader, IffChunk, IffReader, IffSeeker, Truncation};
 use crate::*;
+use std::io::{Read, Seek};
 use std::path::Path;
 
 /// IFF files contain chunks identified by 4 byte ids
@@ -12,6 +16,37 @@ const CAMG: ChunkId = ChunkId::new(b"CAMG");
 const DPI: ChunkId = ChunkId::new(b"DPI ");
 const BODY: ChunkId = ChunkId::new(b"BODY");
 
+/// The form types of images
+const ILBM: ChunkId = ChunkId::new(b"ILBM");
+const PBM: ChunkId = ChunkId::new(b"PBM ");
+const ACBM: ChunkId = ChunkId::new(b"ACBM");
+const RGBN: ChunkId = ChunkId::new(b"RGBN");
+const RGB8: ChunkId = ChunkId::new(b"RGB8");
+const DEEP: ChunkId = ChunkId::new(b"DEEP");
+
+/// The chunks of a DEEP, which describe it rather differently
+const DGBL: ChunkId = ChunkId::new(b"DGBL");
+const DPEL: ChunkId = ChunkId::new(b"DPEL");
+const DLOC: ChunkId = ChunkId::new(b"DLOC");
+const DBOD: ChunkId = ChunkId::new(b"DBOD");
+const TVDC: ChunkId = ChunkId::new(b"TVDC");
+
+/// The bitplanes of an ACBM, which take the place of its BODY
+const ABIT: ChunkId = ChunkId::new(b"ABIT");
+
+/// Color cycling, from DeluxePaint, Graphicraft and DeluxePaint IV
+const CRNG: ChunkId = ChunkId::new(b"CRNG");
+const CCRT: ChunkId = ChunkId::new(b"CCRT");
+const DRNG: ChunkId = ChunkId::new(b"DRNG");
+
+/// Palettes that change line by line, from Sliced HAM, Dynamic HiRes/HAM and PCHG
+const SHAM: ChunkId = ChunkId::new(b"SHAM");
+const CTBL: ChunkId = ChunkId::new(b"CTBL");
+const PCHG: ChunkId = ChunkId::new(b"PCHG");
+
+/// One plane of a ByteRun2 BODY
+const VDAT: ChunkId = ChunkId::new(b"VDAT");
+
 struct RowIter<'a> {
     raw_data: &'a [u8],
     width: usize,
@@ -19,7 +54,7 @@ struct RowIter<'a> {
 }
 
 impl<'a> RowIter<'a> {
-    fn new(raw_data: &[u8], width: usize, compressed: bool) -> RowIter {
+    fn new(raw_data: &[u8], width: usize, compressed: bool) -> RowIter<'_> {
         RowIter {
             raw_data,
             width,
@@ -33,7 +68,7 @@ impl<'a> Iterator for RowIter<'a> {
     type Item = Vec<u8>;
     fn next(&mut self) -> std::option::Option<<Self as std::iter::Iterator>::Item> {
         if self.compressed {
-            match compression::unpacker(&self.raw_data, self.width) {
+            match compression::unpacker(self.raw_data, self.width) {
                 Ok((remaining, row)) => {
                     self.raw_data = remaining;
                     Some(row)
@@ -58,122 +93,456 @@ pub fn read_file<P: AsRef<Path>>(path: P, options: ReadOptions) -> Result<IlbmIm
     // We choose to buffer the entire file, it is quite a bit faster,
     // and Amiga image files tend to be small anyway
     let all_bytes = std::fs::read(path)?;
-    let reader = IffReader::new(std::io::Cursor::new(all_bytes));
-
-    for chunk in reader {
-        debug!("Chunk {}", chunk);
-
-        // We only look at forms of type ILBM, they encapsulate several sub-chunks
-        if chunk.is_form_type(b"ILBM") {
-            let mut image = IlbmImage::default();
-
-            let mut map: Option<ColorMap> = None;
-            let mut got_header = false;
-            let mut got_camg = false;
-
-            for sub_chunk in chunk.sub_chunks() {
-                match sub_chunk.id() {
-                    BMHD => {
-                        read_bitmap_header(sub_chunk, &mut image)?;
-                        debug!("after header {}", image);
-                        if image.masking != Masking::NoMask {
-                            warn!("Image masking (transparency) not supported!");
-                        }
-                        got_header = true;
-                    }
+    read_bytes(&all_bytes, options)
+}
 
-                    CMAP => {
-                        let m = read_color_map(sub_chunk)?;
-                        debug!("Got color map, of map_size {}", m.colors.len());
-                        image.map_size = m.colors.len();
-                        map = Some(m);
-                    }
+pub fn read_reader<R: Read>(mut reader: R, options: ReadOptions) -> Result<IlbmImage> {
+    // Same reasoning as read_file, buffer everything
+    let mut all_bytes = Vec::new();
+    reader.read_to_end(&mut all_bytes)?;
+    read_bytes(&all_bytes, options)
+}
 
-                    CAMG => {
-                        let mode = read_display_mode(sub_chunk)?;
-                        debug!("Got display mode: {}", mode);
-                        got_camg = true;
-                        image.display_mode = mode;
-                    }
+pub fn read_bytes(all_bytes: &[u8], options: ReadOptions) -> Result<IlbmImage> {
+    match find_images(all_bytes, &options, true)?.into_iter().next() {
+        Some((_, image)) => image,
+        None => Err(IlbmError::NoImage),
+    }
+}
 
-                    DPI => {
-                        let dpi = read_dpi(sub_chunk)?;
-                        debug!("Got dpi: {}", dpi);
-                        image.dpi = dpi;
-                    }
+pub fn read_all_file<P: AsRef<Path>>(path: P, options: ReadOptions) -> Result<FoundImages> {
+    let all_bytes = std::fs::read(path)?;
+    read_all_bytes(&all_bytes, options)
+}
 
-                    BODY => {
-                        debug!("Got BODY! {}", image);
-
-                        if !got_header {
-                            return Err(IlbmError::NoHeader);
-                        }
-
-                        // Reportedly, some HAM6 files are missing the CAMG chunk.
-                        // A file with no CAMG chunk, 6 bit planes, and 16 palette colors assumed to be HAM6
-                        if !got_camg && image.planes == 6 && image.map_size == 16 {
-                            // force on HAM
-                            warn!("Looks like HAM6, but didn't get a CAMG, forcing HAM");
-                            image.display_mode = DisplayMode::ham();
-                        }
-
-                        if image.display_mode.is_halfbrite() && image.planes != 6 {
-                            return Err(IlbmError::NotSupported(format!(
-                                "Halfbright only works with 6 planes, but I have {}",
-                                image.planes
-                            )));
-                        }
-
-                        if options.read_pixels {
-                            read_body(sub_chunk, image.display_mode, map, &mut image)?;
-                        }
-
-                        if options.page_scale {
-                            // This is a bit of a heuristic, but
-                            // only the Amiga messes with page sizes where
-                            // the width is so much less that the height,
-                            // and in those cases the pixels are essentially double-wide
-                            if image.page_size.width() < image.page_size.height() {
-                                debug!("Scaling image to suit modern screen aspect ratios!");
-
-                                let old = &image.pixels;
-                                let mut new = Vec::<u8>::with_capacity(image.pixels.len() * 2);
-
-                                // iterate over the old pixels
-                                for i in (0..old.len()).step_by(3) {
-                                    new.push(old[i]);
-                                    new.push(old[i + 1]);
-                                    new.push(old[i + 2]);
-                                    new.push(old[i]);
-                                    new.push(old[i + 1]);
-                                    new.push(old[i + 2]);
-                                }
-
-                                image.pixels = new;
-                                image.size.0 *= 2;
-                            }
-                        }
-
-                        return Ok(image);
-                    }
+pub fn read_all_reader<R: Read>(mut reader: R, options: ReadOptions) -> Result<FoundImages> {
+    let mut all_bytes = Vec::new();
+    reader.read_to_end(&mut all_bytes)?;
+    read_all_bytes(&all_bytes, options)
+}
 
-                    _ => {
-                        debug!("Skipping sub chunk {}", sub_chunk.id());
-                        continue;
-                    }
+pub fn read_all_bytes(all_bytes: &[u8], options: ReadOptions) -> Result<FoundImages> {
+    let found = find_images(all_bytes, &options, false)?;
+    if found.is_empty() {
+        Err(IlbmError::NoImage)
+    } else {
+        Ok(found)
+    }
+}
+
+/// Walk all the chunks, looking for images, or just the first one
+fn find_images(all_bytes: &[u8], options: &ReadOptions, first_only: bool) -> Result<FoundImages> {
+    let reader = IffReader::new(std::io::Cursor::new(all_bytes)).fallible();
+    let mut found = FoundImages::new();
+
+    for chunk in reader.map_while(tolerate_padding) {
+        find_images_in(chunk?, &[], options, first_only, &mut found)?;
+
+        if first_only && !found.is_empty() {
+            break;
+        }
+    }
+
+    Ok(found)
+}
+
+/// Look for images in a chunk, either a FORM ILBM, or a LIST or CAT with images inside.
+/// The props are the PROP chunks of any LISTs we are inside, outermost first.
+/// Problems with an image are kept with the image, anything else is an error
+fn find_images_in(
+    chunk: IffChunk,
+    props: &[IffChunk],
+    options: &ReadOptions,
+    first_only: bool,
+    found: &mut FoundImages,
+) -> Result<()> {
+    debug!("Chunk {}", chunk);
+
+    // We only look at forms holding images, they encapsulate several sub-chunks
+    if let Some(form_type) = image_form_type(&chunk) {
+        let position = ImagePosition {
+            index: found.len(),
+            offset: chunk.offset(),
+        };
+
+        match read_form(chunk, form_type, props, options) {
+            Ok(None) => (),
+            Ok(Some(image)) => found.push((position, Ok(image))),
+            Err(e) => found.push((position, Err(e))),
+        }
+    } else if chunk.is_list() || chunk.is_cat() {
+        // PROPs only apply to the rest of the group they are found in
+        let mut props = props.to_vec();
+
+        for sub_chunk in chunk.sub_chunks().fallible().map_while(tolerate_padding) {
+            let sub_chunk = sub_chunk?;
+
+            // IFF-85 only allows PROPs in a LIST, in a CAT they have no FORMs to apply to
+            if sub_chunk.is_prop() {
+                if chunk.is_list() {
+                    props.push(sub_chunk);
+                } else {
+                    warn!("Ignoring a PROP outside a LIST, at {}", sub_chunk.offset());
+                }
+            } else {
+                find_images_in(sub_chunk, &props, options, first_only, found)?;
+
+                if first_only && !found.is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The type of a FORM, if it is one holding an image we can read
+fn image_form_type(chunk: &IffChunk) -> Option<ChunkId> {
+    match chunk.group_type() {
+        Some(form_type) if chunk.is_form() && is_image_form(form_type) => Some(form_type),
+        _ => None,
+    }
+}
+
+fn is_image_form(form_type: ChunkId) -> bool {
+    [ILBM, PBM, ACBM, RGBN, RGB8, DEEP].contains(&form_type)
+}
+
+/// The chunk holding the pixels, for each type of image FORM
+fn body_id(form_type: ChunkId) -> ChunkId {
+    match form_type {
+        ACBM => ABIT,
+        DEEP => DBOD,
+        _ => BODY,
+    }
+}
+
+/// Read a FORM holding an image, None if it turns out to have no BODY
+fn read_form(
+    chunk: IffChunk,
+    form_type: ChunkId,
+    props: &[IffChunk],
+    options: &ReadOptions,
+) -> Result<Option<IlbmImage>> {
+    let mut state = FormState::new(options);
+    state.apply_props(props, form_type)?;
+    let body_id = body_id(form_type);
+
+    for sub_chunk in chunk.sub_chunks().fallible().map_while(tolerate_padding) {
+        let sub_chunk = sub_chunk?;
+
+        if sub_chunk.id() == body_id {
+            debug!("Got {}! {}", body_id, state.image);
+
+            state.prepare_body()?;
+            let mut image = state.image;
+
+            if options.read_pixels {
+                let palettes = Palettes {
+                    halfbrite: options.halfbrite,
+                    lines: &state.line_palettes,
+                };
+                read_body(sub_chunk, form_type, &state.deep, palettes, &mut image)?;
+            }
+
+            if options.page_scale {
+                scale_page(&mut image);
+            }
+
+            return Ok(Some(image));
+        }
+
+        state.read_property(sub_chunk)?;
+    }
+
+    Ok(None)
+}
+
+/// Plenty of writers leave off the pad byte of the last chunk, which is harmless,
+/// so treat that as the end of the chunks, any other error is passed on
+fn tolerate_padding(chunk: Result<IffChunk>) -> Option<Result<IffChunk>> {
+    match chunk {
+        Err(IlbmError::Truncated {
+            part: Truncation::Padding,
+            offset,
+        }) => {
+            warn!("Missing pad byte at offset {}", offset);
+            None
+        }
+        other => Some(other),
+    }
+}
+
+pub fn probe_file<P: AsRef<Path>>(path: P, options: ReadOptions) -> Result<IlbmImage> {
+    let file = std::fs::File::open(path)?;
+    probe_reader(std::io::BufReader::new(file), options)
+}
+
+/// Like read_bytes, without the pixels, but reading only the header chunks,
+/// anything else (most importantly the BODY) is skipped over
+pub fn probe_reader<R: Read + Seek>(reader: R, options: ReadOptions) -> Result<IlbmImage> {
+    let mut iff = IffSeeker::new(reader);
+
+    match probe_group(&mut iff, u64::MAX, false, Vec::new(), &options)? {
+        Some(mut image) => {
+            if options.page_scale {
+                scale_page(&mut image);
+            }
+            Ok(image)
+        }
+        None => Err(IlbmError::NoImage),
+    }
+}
+
+/// The probing equivalent of find_image, for everything up to the end offset,
+/// in_list is true for the contents of a LIST, the only place a PROP belongs
+fn probe_group<R: Read + Seek>(
+    iff: &mut IffSeeker<R>,
+    end: u64,
+    in_list: bool,
+    mut props: Vec<IffChunk>,
+    options: &ReadOptions,
+) -> Result<Option<IlbmImage>> {
+    while iff.position()? < end {
+        let header = match iff.next_header()? {
+            Some(header) => header,
+            None => break,
+        };
+
+        if header.len >= 4 && header.id.is_group() {
+            let group_type = iff.group_type(&header)?;
+
+            if header.is_form() && is_image_form(group_type) {
+                if let Some(image) = probe_form(iff, &header, group_type, &props, options)? {
+                    return Ok(Some(image));
+                }
+            } else if header.is_list() || header.is_cat() {
+                let group_end = header.data_offset + header.len as u64;
+                let found = probe_group(iff, group_end, header.is_list(), props.clone(), options)?;
+                if let Some(image) = found {
+                    return Ok(Some(image));
+                }
+            } else if header.is_prop() && in_list {
+                // These are small, so just read them
+                props.push(iff.read_chunk(&header)?);
+                continue;
+            }
+        }
+
+        iff.skip(&header)?;
+    }
+
+    Ok(None)
+}
+
+fn probe_form<R: Read + Seek>(
+    iff: &mut IffSeeker<R>,
+    form: &ChunkHeader,
+    form_type: ChunkId,
+    props: &[IffChunk],
+    options: &ReadOptions,
+) -> Result<Option<IlbmImage>> {
+    let mut state = FormState::new(options);
+    state.apply_props(props, form_type)?;
+
+    let form_end = form.data_offset + form.len as u64;
+    let body_id = body_id(form_type);
+
+    while iff.position()? < form_end {
+        let header = match iff.next_header()? {
+            Some(header) => header,
+            None => break,
+        };
+
+        match header.id {
+            id if id == body_id => {
+                state.prepare_body()?;
+                return Ok(Some(state.image));
+            }
+            BMHD | CMAP | CAMG | DPI | CRNG | CCRT | DRNG | DGBL | DPEL | DLOC | TVDC => {
+                let chunk = iff.read_chunk(&header)?;
+                state.read_property(chunk)?;
+            }
+            _ => {
+                debug!("Skipping sub chunk {}", header.id);
+                iff.skip(&header)?;
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// The image, as described by the chunks in a FORM before its BODY
+struct FormState {
+    image: IlbmImage,
+    got_header: bool,
+    got_camg: bool,
+
+    /// Only for DEEP images
+    deep: DeepHeader,
+
+    /// From a SHAM, CTBL or PCHG, if there is one
+    line_palettes: LinePalettes,
+}
+
+impl FormState {
+    fn new(options: &ReadOptions) -> FormState {
+        FormState {
+            image: IlbmImage {
+                pixel_format: options.pixel_format,
+                ..Default::default()
+            },
+            got_header: false,
+            got_camg: false,
+            deep: DeepHeader::default(),
+            line_palettes: LinePalettes::default(),
+        }
+    }
+
+    /// Apply the shared properties from PROP chunks of our form type, in order,
+    /// so those of inner LISTs override the outer ones
+    fn apply_props(&mut self, props: &[IffChunk], form_type: ChunkId) -> Result<()> {
+        for prop in props.iter().filter(|p| p.group_type() == Some(form_type)) {
+            for chunk in prop.sub_chunks().fallible().map_while(tolerate_padding) {
+                self.read_property(chunk?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Take what we need from a chunk that describes the image, anything we don't understand is skipped
+    fn read_property(&mut self, chunk: IffChunk) -> Result<()> {
+        let image = &mut self.image;
+
+        match chunk.id() {
+            BMHD => {
+                read_bitmap_header(chunk, image)?;
+                debug!("after header {}", image);
+                self.got_header = true;
+            }
+
+            CMAP => {
+                let m = read_color_map(chunk)?;
+                debug!("Got color map, of map_size {}", m.colors.len());
+                image.map_size = m.colors.len();
+                image.color_map = Some(m);
+            }
+
+            CAMG => {
+                let mode = read_display_mode(chunk)?;
+                debug!("Got display mode: {}", mode);
+
+                // As getcamg does, a CAMG we can't make sense of is as good as none
+                if mode.is_bogus() {
+                    warn!("Ignoring bogus CAMG 0x{:X}", mode.value());
                 }
+                self.got_camg = !mode.is_bogus();
+                image.display_mode = mode;
+            }
+
+            DPI => {
+                let dpi = read_dpi(chunk)?;
+                debug!("Got dpi: {}", dpi);
+                image.dpi = dpi;
+            }
+
+            CRNG => image.color_cycles.push(cycle::read_crng(chunk)?),
+            CCRT => image.color_cycles.push(cycle::read_ccrt(chunk)?),
+            DRNG => image.color_cycles.push(cycle::read_drng(chunk)?),
+
+            SHAM => self.line_palettes = dynamic::read_sham(chunk)?,
+            CTBL => self.line_palettes = dynamic::read_ctbl(chunk)?,
+            PCHG => self.line_palettes = dynamic::read_pchg(chunk)?,
+
+            DGBL => {
+                self.deep.read_globals(chunk, image)?;
+                debug!("after globals {}", image);
+                self.got_header = true;
+            }
+
+            DPEL => self.deep.read_elements(chunk, image)?,
+            DLOC => self.deep.read_location(chunk, image)?,
+            TVDC => self.deep.read_tvdc(chunk)?,
+
+            _ => {
+                debug!("Skipping sub chunk {}", chunk.id());
             }
         }
+
+        Ok(())
+    }
+
+    /// We reached the BODY, so check we have everything we need to read it
+    fn prepare_body(&mut self) -> Result<()> {
+        let image = &mut self.image;
+
+        if !self.got_header {
+            return Err(IlbmError::NoHeader);
+        }
+
+        // Reportedly, some HAM6 files are missing the CAMG chunk.
+        // A file with no CAMG chunk, 6 bit planes, and 16 palette colors assumed to be HAM6
+        if !self.got_camg && image.planes == 6 && image.map_size == 16 {
+            // force on HAM
+            warn!("Looks like HAM6, but didn't get a CAMG, forcing HAM");
+            image.display_mode = DisplayMode::ham();
+        }
+
+        if image.display_mode.is_ham() {
+            // HAM6 (OCS/ECS) and HAM8 (AGA) are the only ones that make sense, with
+            // fewer planes there would be no bits left over to index the color map
+            if image.planes != 6 && image.planes != 8 {
+                return Err(IlbmError::NotSupported(format!(
+                    "HAM needs 6 or 8 planes, but I have {}",
+                    image.planes
+                )));
+            }
+
+            // The hardware ignores halfbrite in HAM mode, so we do too (see DisplayMode::effective)
+            if image.display_mode.is_halfbrite() {
+                warn!("Both HAM and halfbrite set, using HAM");
+            }
+        }
+
+        // Halfbrite is the sixth plane, with fewer no pixel is ever darkened, which is harmless
+        if image.display_mode.effective().is_halfbrite() && image.planes > 6 {
+            return Err(IlbmError::NotSupported(format!(
+                "Halfbright only works with 6 planes, but I have {}",
+                image.planes
+            )));
+        }
+
+        Ok(())
     }
+}
 
-    Err(IlbmError::NoImage)
+/// This is a bit of a heuristic, but
+/// only the Amiga messes with page sizes where
+/// the width is so much less that the height,
+/// and in those cases the pixels are essentially double-wide
+fn scale_page(image: &mut IlbmImage) {
+    if image.page_size.width() < image.page_size.height() {
+        debug!("Scaling image to suit modern screen aspect ratios!");
+
+        let old = &image.pixels;
+        let mut new = Vec::<u8>::with_capacity(image.pixels.len() * 2);
+
+        // iterate over the old pixels
+        for pixel in old.chunks(image.pixel_format.bytes_per_pixel()) {
+            new.extend_from_slice(pixel);
+            new.extend_from_slice(pixel);
+        }
+
+        image.pixels = new;
+        image.size.0 *= 2;
+    }
 }
 
 fn read_dpi(chunk: IffChunk) -> Result<Size2D> {
-    Ok(Size2D(
-        chunk.data().get_u16()? as usize,
-        chunk.data().get_u16()? as usize,
-    ))
+    let mut buf = chunk.data();
+    Ok(Size2D(buf.get_u16()? as usize, buf.get_u16()? as usize))
 }
 
 fn read_display_mode(chunk: IffChunk) -> Result<DisplayMode> {
@@ -181,7 +550,7 @@ fn read_display_mode(chunk: IffChunk) -> Result<DisplayMode> {
 }
 
 fn read_color_map(chunk: IffChunk) -> Result<ColorMap> {
-    let mut buf = &chunk.data()[..];
+    let mut buf = chunk.data();
 
     let count = buf.len() / 3;
 
@@ -207,8 +576,11 @@ fn read_color_map(chunk: IffChunk) -> Result<ColorMap> {
         colors.push(RgbValue(red, green, blue));
     }
 
+    let original = colors.clone();
+
     // This is where we fix up 4 bit color maps, if we need to
-    if !found_low_bits {
+    let fixed_up = !found_low_bits && !colors.is_empty();
+    if fixed_up {
         info!("Found old color map, fixing up!...");
         colors.iter_mut().for_each(|color| {
             *color = RgbValue(
@@ -219,27 +591,207 @@ fn read_color_map(chunk: IffChunk) -> Result<ColorMap> {
         });
     }
 
-    Ok(ColorMap { colors })
+    Ok(ColorMap {
+        colors,
+        original,
+        fixed_up,
+    })
 }
 
 fn read_body(
     chunk: IffChunk,
-    mode: DisplayMode,
-    map: Option<ColorMap>,
+    form_type: ChunkId,
+    deep: &DeepHeader,
+    palettes: Palettes,
     image: &mut IlbmImage,
 ) -> Result<()> {
     debug!("{}", image);
-    match map {
-        Some(map) => read_body_with_cmap(chunk, mode, map, image),
-        None => read_body_no_map(chunk, image),
+
+    let mode = image.display_mode.effective();
+
+    // Only now does an unknown compression matter, the header was still worth having
+    if let Compression::NotSupported(x) = image.compression {
+        return Err(IlbmError::NotSupported(format!("Compression type {}", x)));
+    }
+
+    if image.pixel_format == PixelFormat::Indexed
+        && (mode.is_ham() || image.color_map.is_none() || !palettes.lines.is_none())
+    {
+        return Err(IlbmError::NotSupported(
+            "Indexed pixels for HAM, images with no color map, or a palette per line"
+                .to_string(),
+        ));
+    }
+
+    // Bytes per plane row (always EVEN)
+    let row_stride = image.size.width().div_ceil(16) * 2;
+
+    match form_type {
+        PBM => read_body_chunky(chunk, mode, palettes, image),
+        RGBN | RGB8 => read_body_impulse(chunk, form_type, image),
+        DEEP => deep::read_body(chunk, deep, image),
+        ACBM => {
+            let body = interleave_planes(chunk.data(), row_stride, image)?;
+            read_body_planar(RowIter::new(&body, row_stride, false), mode, palettes, image)
+        }
+        _ if image.compression == Compression::ByteRun2 => {
+            let body = unpack_vdat(chunk, row_stride, image)?;
+            read_body_planar(RowIter::new(&body, row_stride, false), mode, palettes, image)
+        }
+        _ => {
+            let compressed = is_row_compressed(image.compression)?;
+            let rows = RowIter::new(chunk.data(), row_stride, compressed);
+            read_body_planar(rows, mode, palettes, image)
+        }
+    }
+}
+
+/// Where the colors come from, as well as the CMAP
+#[derive(Clone, Copy)]
+struct Palettes<'a> {
+    halfbrite: HalfbritePolicy,
+
+    /// Colors that change from one line to the next
+    lines: &'a LinePalettes,
+}
+
+/// Whether rows need unpacking with ByteRun1, for forms that only know that
+fn is_row_compressed(compression: Compression) -> Result<bool> {
+    match compression {
+        Compression::None => Ok(false),
+        Compression::ByteRun1 => Ok(true),
+        other => Err(IlbmError::NotSupported(format!(
+            "{:?} compression for rows",
+            other
+        ))),
+    }
+}
+
+/// A ByteRun2 BODY is a VDAT chunk for each plane (and the mask, if there is one),
+/// each unpacks to a whole plane, so they go together just like an ACBM
+fn unpack_vdat(chunk: IffChunk, row_stride: usize, image: &IlbmImage) -> Result<Vec<u8>> {
+    let height = image.size.height();
+    let mut planes = Vec::new();
+
+    for vdat in IffReader::new(std::io::Cursor::new(chunk.data())).fallible() {
+        let vdat = vdat?;
+        if vdat.id() == VDAT {
+            planes.extend(compression::vertical_unpacker(vdat.data(), row_stride, height)?);
+        } else {
+            debug!("Skipping BODY sub chunk {}", vdat.id());
+        }
+    }
+
+    interleave_planes(&planes, row_stride, image)
+}
+
+/// Rows of planes hold indexes into the color map, if there is one, otherwise RGB values
+fn read_body_planar(
+    rows: RowIter,
+    mode: DisplayMode,
+    palettes: Palettes,
+    image: &mut IlbmImage,
+) -> Result<()> {
+    match image.color_map.clone() {
+        Some(map) => read_body_with_cmap(rows, mode, map, palettes, image),
+        None => read_body_no_map(rows, image),
+    }
+}
+
+/// RGBN and RGB8 bodies, from Impulse's Turbo Silver and Imagine, are runs of pixels,
+/// each a color, a genlock bit (set where the background should show through),
+/// and a repeat count, RGBN packs 4 bit channels into a word, RGB8 8 bit channels into a long.
+/// Runs carry on from one row to the next
+fn read_body_impulse(chunk: IffChunk, form_type: ChunkId, image: &mut IlbmImage) -> Result<()> {
+    if image.pixel_format == PixelFormat::Indexed {
+        return Err(IlbmError::NotSupported(format!(
+            "Indexed pixels for {}",
+            form_type
+        )));
+    }
+
+    let Size2D(width, height) = image.size;
+    let total = width * height;
+
+    // These grow with each run, rather than trusting the size in the header
+    let mut rgb = Vec::<u8>::new();
+    let mut alpha = Vec::<u8>::new();
+
+    let mut data = chunk.data();
+
+    while alpha.len() < total {
+        let (color, genlock, mut count) = if form_type == RGBN {
+            let value = data.get_u16()?;
+            let nibble = |shift: u16| ((value >> shift) & 0xf) as u8 * 17;
+            (
+                RgbValue(nibble(12), nibble(8), nibble(4)),
+                value & 0x8 != 0,
+                (value & 0x7) as usize,
+            )
+        } else {
+            let [r, g, b, n] = data.get_u32()?.to_be_bytes();
+            (RgbValue(r, g, b), n & 0x80 != 0, (n & 0x7f) as usize)
+        };
+
+        // Longer runs have a zero count, and the real one in the following byte,
+        // or if that is zero too, the following word
+        if count == 0 {
+            count = data.get_u8()? as usize;
+        }
+        if count == 0 {
+            count = data.get_u16()? as usize;
+        }
+
+        let count = count.min(total - alpha.len());
+        for _ in 0..count {
+            rgb.extend_from_slice(&[color.0, color.1, color.2]);
+            alpha.push(if genlock { 0 } else { 255 });
+        }
     }
+
+    image.pixels = apply_alpha(rgb, &alpha, image.pixel_format);
+    Ok(())
+}
+
+/// An ACBM stores each plane whole, one after the other (followed by the mask plane,
+/// if there is one), rather than a row of each in turn, so shuffle them into BODY order
+fn interleave_planes(abit: &[u8], row_stride: usize, image: &IlbmImage) -> Result<Vec<u8>> {
+    let Size2D(width, height) = image.size;
+    let planes = image.planes + (image.masking == Masking::HasMask) as usize;
+    let plane_size = row_stride * height;
+
+    // Check there is enough before making room for it all, the sizes in the header
+    // may be nonsense (and big enough to overflow, on a 32 bit target)
+    let needed = plane_size.saturating_mul(planes);
+    if abit.len() < needed {
+        return Err(IlbmError::InvalidData(format!(
+            "ABIT of {} bytes is too short for {} planes of {}x{}",
+            abit.len(),
+            planes,
+            width,
+            height
+        )));
+    }
+
+    let mut body = Vec::with_capacity(needed);
+    for row in 0..height {
+        for plane in 0..planes {
+            let start = plane * plane_size + row * row_stride;
+            body.extend_from_slice(&abit[start..start + row_stride]);
+        }
+    }
+    Ok(body)
 }
 
+/// A row of color map indexes, and the alpha from its mask plane, if it has one
+type IndexRow = (Vec<u8>, Option<Vec<u8>>);
+
 /// Read a body using a color map, pixel data is interpreted as indexes into the map  
 fn read_body_with_cmap(
-    chunk: IffChunk,
+    mut rows: RowIter,
     mode: DisplayMode,
     color_map: ColorMap,
+    palettes: Palettes,
     image: &mut IlbmImage,
 ) -> Result<()> {
     // Having a CMAP implies certain limitations, here we limit color indices to a u8
@@ -250,162 +802,348 @@ fn read_body_with_cmap(
         ));
     }
 
-    let Size2D(width, height) = image.size;
+    let width = image.size.width();
     let planes = image.planes;
+    let has_mask = image.masking == Masking::HasMask;
+
+    resolve_indexes(mode, color_map, palettes, image, || {
+        // This is the row data we are trying to assemble from planes, an array of bytes,
+        // having no more than 8 planes means the values always fit
+        let row: Vec<u8> = read_plane_row(&mut rows, planes, width)?
+            .into_iter()
+            .map(|p| p as u8)
+            .collect();
+
+        let mask = if has_mask {
+            Some(read_mask_row(&mut rows, width)?)
+        } else {
+            None
+        };
+
+        Ok((row, mask))
+    })
+}
 
-    // Bytes per row (always EVEN)
-    let row_stride = ((width + 15) / 16) * 2;
+/// Read a chunky body, as found in PBM files from DeluxePaint for the PC,
+/// where each pixel is simply a byte, and rows are padded to an even length
+fn read_body_chunky(
+    chunk: IffChunk,
+    mode: DisplayMode,
+    palettes: Palettes,
+    image: &mut IlbmImage,
+) -> Result<()> {
+    let color_map = match image.color_map.clone() {
+        Some(map) => map,
+        None => return Err(IlbmError::NotSupported("PBM with no color map".to_string())),
+    };
+
+    let width = image.size.width();
+    let row_stride = width + (width & 1);
+
+    let compressed = is_row_compressed(image.compression)?;
+    let mut rows = RowIter::new(chunk.data(), row_stride, compressed);
+
+    resolve_indexes(mode, color_map, palettes, image, || {
+        let mut row = rows.next().ok_or(IlbmError::NoData)?;
+        row.truncate(width);
+        Ok((row, None))
+    })
+}
+
+/// Turn rows of color map indexes into pixels, in whatever format was asked for,
+/// working out alpha from the mask, the transparent color, or the lasso, as we go
+fn resolve_indexes(
+    mode: DisplayMode,
+    color_map: ColorMap,
+    palettes: Palettes,
+    image: &mut IlbmImage,
+    mut next_row: impl FnMut() -> Result<IndexRow>,
+) -> Result<()> {
+    let Size2D(width, height) = image.size;
+    let planes = image.planes;
 
-    let mut rows = RowIter::new(chunk.data(), row_stride, image.compression);
+    let indexed = image.pixel_format == PixelFormat::Indexed;
 
-    // We assemble all the resolved RGB values in here
-    let mut pixels = Vec::<u8>::with_capacity(3 * width * height);
+    // Halfbrite pixels are resolved through the whole 64 color map,
+    // and indexed ones need it to make sense of the upper half
+    let mut color_map = if mode.is_halfbrite() {
+        let full_map = halfbrite_map(&color_map, palettes.halfbrite);
+        if indexed {
+            image.color_map = Some(full_map.clone());
+        }
+        full_map
+    } else {
+        color_map
+    };
 
-    for _row in 0..height {
-        // This is the row data we are trying to assemble from planes, an array of bytes
-        let mut row = vec![0u8; width];
+    // We assemble all the resolved RGB values (or the indexes) in here, growing as rows
+    // are read, the size in the header may be far more than the body can fill
+    let resolved_size = if indexed { 1 } else { 3 };
+    let mut pixels = Vec::<u8>::new();
 
-        // Each plane gives us one bit, this one
-        let mut plane_bit: u8 = 1;
-        for _plane_number in 0..planes {
-            let plane_data = rows.next().ok_or(IlbmError::NoData)?;
+    // and the alpha values, if the image has a mask
+    let mut alpha = Vec::<u8>::new();
 
-            // Read planes, each plane contributes 1 bit
+    // A lasso needs the whole image before we can work out what is transparent
+    let mut lasso_indices = Vec::<u8>::new();
 
-            for (offset, byte) in plane_data.iter().enumerate() {
-                let mut plane_byte = *byte;
+    let line_changes = palettes.lines.line_changes(height, mode.is_laced());
 
-                for b in 0..8 {
-                    if plane_byte & 0x80 != 0 {
-                        let index = (offset * 8) + b;
+    for changes in line_changes {
+        let (row, mask) = next_row()?;
 
-                        // Check width, because of padding and rounding, we may
-                        //  have more data than the width dictates
-                        if index < width {
-                            // Bit is on, so set the bit, corresponding with the plane, in the row data
-                            row[index] |= plane_bit;
-                        }
-                    }
-                    plane_byte <<= 1;
-                }
+        // Change the palette, for this line and those after, registers past the end
+        // of the color map are black, until they are changed
+        for (register, color) in changes {
+            let colors = &mut color_map.colors;
+            if register >= colors.len() {
+                colors.resize(register + 1, RgbValue::default());
             }
+            colors[register] = color;
 
-            // planes start at the low bit, so shift left the bit we plan to set next
-            plane_bit <<= 1;
+            // The darker twin of a halfbrite color follows it
+            let derived = palettes.halfbrite == HalfbritePolicy::Derive;
+            if mode.is_halfbrite() && derived && register < 32 {
+                colors[register + 32] = RgbValue(color.0 >> 1, color.1 >> 1, color.2 >> 1);
+            }
         }
 
-        if image.masking == Masking::HasMask {
-            // Read mask plane, we don't support this yet,
-            // need to go RGBA to do so
-
-            // get the next row
-            let _row_data = rows.next().ok_or(IlbmError::NoData)?;
+        if let Some(mask) = mask {
+            alpha.extend(mask);
+        } else if image.masking == Masking::HasTransparentColor {
+            // Compare the raw value, before any HAM or halfbrite interpretation
+            let transparent = image.transparent_color;
+            alpha.extend(
+                row.iter()
+                    .map(|&p| if p as usize == transparent { 0 } else { 255 }),
+            );
+        } else if image.masking == Masking::Lasso {
+            lasso_indices.extend_from_slice(&row);
         }
 
-        if mode.is_ham() {
+        if indexed {
+            push_row_indexes(row, image.color_map.as_ref().unwrap(), &mut pixels)?;
+        } else if mode.is_ham() {
             push_row_bytes_ham(row, planes, &color_map, &mut pixels)?;
-        } else if mode.is_halfbrite() {
-            push_row_bytes_halfbrite(row, &color_map, &mut pixels)?;
         } else {
             push_row_bytes(row, &color_map, &mut pixels)?;
         }
     }
 
-    assert_eq!(pixels.len(), 3 * width * height);
-    image.pixels = pixels;
+    if image.masking == Masking::Lasso {
+        alpha = lasso_alpha(&lasso_indices, width, height, image.transparent_color);
+    }
+
+    assert_eq!(pixels.len(), resolved_size * width * height);
+    image.pixels = apply_alpha(pixels, &alpha, image.pixel_format);
     Ok(())
 }
 
-/// Read a body with no color map, so HAM (6 planes) or deep (24 or 32)
-fn read_body_no_map(chunk: IffChunk, image: &mut IlbmImage) -> Result<()> {
-    // Having no CMAP means we support up to 32 planes (although 24 is more common)
-    // so we build planes into a single u32
-    if image.planes > 32 {
-        return Err(IlbmError::NotSupported(
-            "Too many plans for deep color!".to_string(),
-        ));
-    }
+/// DeluxePaint lasso brushes are transparent outside the lasso, which is found
+/// the way DPaint did it, by flood filling the transparent color inwards from the
+/// border of the image. The transparent color inside the lasso stays opaque
+fn lasso_alpha(indices: &[u8], width: usize, height: usize, transparent: usize) -> Vec<u8> {
+    let mut alpha = vec![255u8; width * height];
 
-    let Size2D(width, height) = image.size;
-    let planes = image.planes;
+    let is_transparent = |i: usize| indices[i] as usize == transparent;
 
-    // Bytes per row (always EVEN)
-    let row_stride = ((width + 15) / 16) * 2;
+    // Seed the fill with every border pixel of the transparent color
+    let mut pending: Vec<usize> = (0..width)
+        .flat_map(|x| vec![x, (height - 1) * width + x])
+        .chain((0..height).flat_map(|y| vec![y * width, y * width + width - 1]))
+        .filter(|&i| is_transparent(i))
+        .collect();
 
-    let mut rows = RowIter::new(chunk.data(), row_stride, image.compression);
+    while let Some(i) = pending.pop() {
+        if alpha[i] == 0 {
+            continue;
+        }
+        alpha[i] = 0;
 
-    // We assemble all the resolved RGB values in here
-    let mut pixels = Vec::<u8>::with_capacity(3 * width * height);
+        let (x, y) = (i % width, i / width);
+        let mut visit = |n: usize| {
+            if alpha[n] != 0 && is_transparent(n) {
+                pending.push(n);
+            }
+        };
 
-    for _row in 0..height {
-        // This is the row data we are trying to assemble from planes, an array of 32 bit values we will interpret as RGB
-        let mut row = vec![0u32; width];
+        if x > 0 {
+            visit(i - 1);
+        }
+        if x + 1 < width {
+            visit(i + 1);
+        }
+        if y > 0 {
+            visit(i - width);
+        }
+        if y + 1 < height {
+            visit(i + width);
+        }
+    }
 
-        // Each plane gives us one bit, this one
-        let mut plane_bit: u32 = 1;
-        for _plane_number in 0..planes {
-            let plane_data = rows.next().ok_or(IlbmError::NoData)?;
+    alpha
+}
 
-            // Read planes, each plane contributes 1 bit
+/// Read a body with no color map, so deep color. The planes are split evenly
+/// between red, green and blue (24 is most common, but 12 and 18 turn up),
+/// with the lowest planes red. 32 planes are 8 bits each of red, green, blue and alpha
+fn read_body_no_map(mut rows: RowIter, image: &mut IlbmImage) -> Result<()> {
+    // HAM and halfbrite pixels are indexes, so they are nothing without their colors
+    let mode = image.display_mode.effective();
+    if mode.is_ham() || mode.is_halfbrite() {
+        return Err(IlbmError::InvalidData(format!(
+            "{} image with no color map",
+            if mode.is_ham() { "HAM" } else { "Halfbrite" }
+        )));
+    }
 
-            for (offset, byte) in plane_data.iter().enumerate() {
-                let mut plane_byte = *byte;
+    // Having no CMAP means we support up to 32 planes (although 24 is more common)
+    // so we build planes into a single u32. Fewer planes than 12 are far more likely
+    // to be indexes that lost their CMAP than a handful of shades of each channel
+    let planes = image.planes;
+    let (channels, bits) = match planes {
+        32 => (4, 8),
+        12 | 18 | 24 => (3, planes / 3),
+        _ => {
+            return Err(IlbmError::NotSupported(format!(
+                "Deep color with {} planes",
+                planes
+            )))
+        }
+    };
 
-                for b in 0..8 {
-                    if plane_byte & 0x80 != 0 {
-                        let index = (offset * 8) + b;
+    let Size2D(width, height) = image.size;
 
-                        // Check width, because of padding and rounding, we may
-                        //  have more data than the width dictates
-                        if index < width {
-                            // Bit is on, so set the bit, corresponding with the plane, in the row data
-                            row[index] |= plane_bit;
-                        }
-                    }
-                    plane_byte <<= 1;
-                }
-            }
+    // We assemble all the resolved RGB values in here, growing as rows are read
+    let mut pixels = Vec::<u8>::new();
 
-            // planes start at the low bit, so shift left the bit we plan to set next
-            plane_bit <<= 1;
-        }
+    // and the alpha values, if the image has a mask or an alpha channel
+    let mut alpha = Vec::<u8>::new();
 
-        if image.masking == Masking::HasMask {
-            // Read mask plane, we don't support this yet,
-            // need to go RGBA to do so
+    let channel_mask = (1u32 << bits) - 1;
+    let channel = |p: u32, c: usize| scale_channel((p >> (c * bits)) & channel_mask, bits);
 
-            // get the next row
-            let _row_data = rows.next().ok_or(IlbmError::NoData)?;
-        }
+    for _row in 0..height {
+        // This is the row data we are trying to assemble from planes, an array of 32 bit values we will interpret as RGB
+        let row = read_plane_row(&mut rows, planes, width)?;
 
-        // Resolve without color map
-        for p in row {
-            let rgb = p.to_be_bytes();
+        let mask = if image.masking == Masking::HasMask {
+            read_mask_row(&mut rows, width)?
+        } else {
+            vec![255; width]
+        };
 
-            // No color map, use value as is, ignore top byte, if it's there, it's alpha
-            pixels.push(rgb[3]);
-            pixels.push(rgb[2]);
-            pixels.push(rgb[1]);
+        // Resolve without color map
+        for (p, m) in row.into_iter().zip(mask) {
+            pixels.extend_from_slice(&[channel(p, 0), channel(p, 1), channel(p, 2)]);
+
+            if channels == 4 {
+                // A clear mask bit wins over the alpha channel
+                alpha.push(channel(p, 3).min(m));
+            } else if image.masking == Masking::HasMask {
+                alpha.push(m);
+            }
         }
     }
 
     assert_eq!(pixels.len(), 3 * width * height);
-    image.pixels = pixels;
+    image.pixels = apply_alpha(pixels, &alpha, image.pixel_format);
     Ok(())
 }
 
-/// simple case where we simply index into the pixel map
-fn push_row_bytes(row: Vec<u8>, color_map: &ColorMap, pixels: &mut Vec<u8>) -> Result<()> {
-    // Resolve through color map, and add to output vector
-    for p in row {
-        let index = p as usize;
-        let map_size = color_map.colors.len();
+/// Scale a channel of fewer than 8 bits to the full 0 to 255 range
+fn scale_channel(value: u32, bits: usize) -> u8 {
+    let max = (1u32 << bits) - 1;
+    ((value * 255 + max / 2) / max) as u8
+}
 
-        if index >= map_size {
-            return Err(IlbmError::NoMapEntry { index, map_size });
-        }
+/// Assemble one row of pixel values, from the next `planes` rows of plane data.
+/// Each plane contributes one bit to every pixel, the first plane is the low bit
+fn read_plane_row(rows: &mut RowIter, planes: usize, width: usize) -> Result<Vec<u32>> {
+    let mut row = vec![0u32; width];
 
-        let rgb = color_map.colors[index];
+    // Each plane gives us one bit, this one
+    let mut plane_bit: u32 = 1;
+    for _plane_number in 0..planes {
+        let plane_data = rows.next().ok_or(IlbmError::NoData)?;
+
+        // Read planes, each plane contributes 1 bit
+
+        for (offset, byte) in plane_data.iter().enumerate() {
+            let mut plane_byte = *byte;
+
+            for b in 0..8 {
+                if plane_byte & 0x80 != 0 {
+                    let index = (offset * 8) + b;
+
+                    // Check width, because of padding and rounding, we may
+                    //  have more data than the width dictates
+                    if index < width {
+                        // Bit is on, so set the bit, corresponding with the plane, in the row data
+                        row[index] |= plane_bit;
+                    }
+                }
+                plane_byte <<= 1;
+            }
+        }
+
+        // planes start at the low bit, so shift left the bit we plan to set next
+        plane_bit <<= 1;
+    }
+
+    Ok(row)
+}
+
+/// The mask plane follows the image planes in each row, a set bit
+/// means the pixel is opaque, clear means transparent
+fn read_mask_row(rows: &mut RowIter, width: usize) -> Result<Vec<u8>> {
+    let mask = read_plane_row(rows, 1, width)?;
+    Ok(mask.iter().map(|&m| if m != 0 { 255 } else { 0 }).collect())
+}
+
+/// Convert resolved RGB triples into the requested pixel format, pixels
+/// with no alpha value (because there was no mask) are opaque
+pub(crate) fn apply_alpha(rgb: Vec<u8>, alpha: &[u8], format: PixelFormat) -> Vec<u8> {
+    match format {
+        PixelFormat::Rgb | PixelFormat::Indexed => rgb,
+        PixelFormat::Rgba => {
+            let mut rgba = Vec::<u8>::with_capacity(rgb.len() / 3 * 4);
+            for (i, pixel) in rgb.chunks(3).enumerate() {
+                rgba.extend_from_slice(pixel);
+                rgba.push(alpha.get(i).copied().unwrap_or(255));
+            }
+            rgba
+        }
+    }
+}
+
+/// No resolving at all, we keep the indexes, but check they are in the map
+fn push_row_indexes(row: Vec<u8>, color_map: &ColorMap, pixels: &mut Vec<u8>) -> Result<()> {
+    let map_size = color_map.colors.len();
+
+    if let Some(&index) = row.iter().find(|&&p| p as usize >= map_size) {
+        return Err(IlbmError::NoMapEntry {
+            index: index as usize,
+            map_size,
+        });
+    }
+
+    pixels.extend(row);
+    Ok(())
+}
+
+/// simple case where we simply index into the pixel map
+fn push_row_bytes(row: Vec<u8>, color_map: &ColorMap, pixels: &mut Vec<u8>) -> Result<()> {
+    // Resolve through color map, and add to output vector
+    for p in row {
+        let index = p as usize;
+        let map_size = color_map.colors.len();
+
+        if index >= map_size {
+            return Err(IlbmError::NoMapEntry { index, map_size });
+        }
+
+        let rgb = color_map.colors[index];
 
         pixels.push(rgb.0);
         pixels.push(rgb.1);
@@ -428,7 +1166,8 @@ fn push_row_bytes_ham(
     let map_size = color_map.colors.len();
 
     // In ham, we steal two planes to determine the modify part
-    // and mask the color index appropriately
+    // and mask the color index appropriately, prepare_body made sure there are 6 or 8
+    debug_assert!(planes == 6 || planes == 8);
     let mod_shift = planes - 2;
     let index_mask = (1 << mod_shift) - 1;
 
@@ -503,52 +1242,45 @@ fn push_row_bytes_ham(
     Ok(())
 }
 
-/// HalfBrite is relatively simple, we need only half the colors in the color map,
-/// as one bit (from the last plane) tells us to simply half (darken) what the rest
-/// of the index tells us
-fn push_row_bytes_halfbrite(
-    row: Vec<u8>,
-    color_map: &ColorMap,
-    pixels: &mut Vec<u8>,
-) -> Result<()> {
-    // Resolve through color map, and add to output vector, but use only half the map
-    // darkening pixels in the upper half
-
-    // Note, we rely here on having 6 planes, we already validated that
-    let low_bits = 0x1f;
-    let half_bit = 0x20;
-
-    for p in row {
-        let index = (p & low_bits) as usize;
-
-        let map_size = color_map.colors.len();
-
-        if index >= map_size {
-            return Err(IlbmError::NoMapEntry { index, map_size });
-        }
+/// HalfBrite is relatively simple, one bit (from the last plane) tells us to half
+/// (darken) the color the rest of the index picks from the lower 32. So we build the
+/// whole 64 color map, deriving the upper half, or taking it from the CMAP if asked
+/// to and it is there. Missing colors in the lower half are black, as the Amiga
+/// color registers would most likely be
+fn halfbrite_map(color_map: &ColorMap, policy: HalfbritePolicy) -> ColorMap {
+    let stored = &color_map.colors;
+    if stored.len() < 32 {
+        warn!("Halfbrite with only {} colors, the rest are black", stored.len());
+    }
 
-        let rgb = color_map.colors[index];
+    let mut colors: Vec<RgbValue> = (0..32)
+        .map(|i| stored.get(i).copied().unwrap_or_default())
+        .collect();
 
-        if p & half_bit != 0 {
-            // Half brightness
-            pixels.push(rgb.0 >> 1);
-            pixels.push(rgb.1 >> 1);
-            pixels.push(rgb.2 >> 1);
-        } else {
-            // Normal
-            pixels.push(rgb.0);
-            pixels.push(rgb.1);
-            pixels.push(rgb.2);
-        }
+    for i in 0..32 {
+        let darker = RgbValue(colors[i].0 >> 1, colors[i].1 >> 1, colors[i].2 >> 1);
+        colors.push(match policy {
+            HalfbritePolicy::Derive => darker,
+            HalfbritePolicy::TrustStored => stored.get(32 + i).copied().unwrap_or(darker),
+        });
     }
 
-    Ok(())
+    ColorMap {
+        colors,
+        ..color_map.clone()
+    }
 }
 
 fn read_bitmap_header(chunk: IffChunk, image: &mut IlbmImage) -> Result<()> {
-    let mut buf = &chunk.data()[..];
+    let mut buf = chunk.data();
+
+    if buf.len() < 20 {
+        return Err(IlbmError::InvalidHeader {
+            expected: "20 bytes of BMHD".to_string(),
+            actual: format!("{} bytes", buf.len()),
+        });
+    }
 
-    assert!(buf.len() >= 20);
     image.size = Size2D(buf.get_u16()? as usize, buf.get_u16()? as usize);
 
     let _x = buf.get_i16()?;
@@ -556,7 +1288,7 @@ fn read_bitmap_header(chunk: IffChunk, image: &mut IlbmImage) -> Result<()> {
 
     image.planes = buf.get_u8()? as usize;
     image.masking = as_masking(buf.get_u8()?);
-    image.compression = buf.get_u8()? != 0;
+    image.compression = as_compression(buf.get_u8()?);
 
     let _pad = buf.get_u8()?;
 
@@ -578,3 +1310,1470 @@ fn read_bitmap_header(chunk: IffChunk, image: &mut IlbmImage) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::*;
+
+    /// Everything needed to build a small ILBM file in memory
+    #[derive(Default)]
+    pub(crate) struct TestImage {
+        pub width: usize,
+        pub height: usize,
+        pub planes: usize,
+        pub masking: u8,
+        pub compression: u8,
+        pub transparent: u16,
+        pub cmap: Option<Vec<u8>>,
+        pub camg: Option<u32>,
+        /// One value per pixel, bit n goes in plane n
+        pub pixels: Vec<u32>,
+        /// One value per pixel, only used when masking is 1
+        pub mask: Vec<bool>,
+        /// Write a PBM, one byte per pixel, rather than an ILBM
+        pub chunky: bool,
+        /// Write an ACBM, each plane whole, rather than an ILBM
+        pub contiguous: bool,
+    }
+
+    pub(crate) fn chunk(id: &[u8; 4], data: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(id);
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend_from_slice(data);
+        if data.len() & 1 != 0 {
+            out.push(0);
+        }
+    }
+
+    /// Pack a row as literal runs only, which is valid, if not very compact, ByteRun1
+    fn pack_literal(row: &[u8], out: &mut Vec<u8>) {
+        for run in row.chunks(128) {
+            out.push((run.len() - 1) as u8);
+            out.extend_from_slice(run);
+        }
+    }
+
+    fn plane_row(values: &[u32], bit: u32, row_stride: usize) -> Vec<u8> {
+        let mut row = vec![0u8; row_stride];
+        for (x, v) in values.iter().enumerate() {
+            if v & bit != 0 {
+                row[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        row
+    }
+
+    pub(crate) fn ilbm_bytes(test: &TestImage) -> Vec<u8> {
+        let mut bmhd = Vec::new();
+        bmhd.extend_from_slice(&(test.width as u16).to_be_bytes());
+        bmhd.extend_from_slice(&(test.height as u16).to_be_bytes());
+        bmhd.extend_from_slice(&[0, 0, 0, 0]);
+        bmhd.push(test.planes as u8);
+        bmhd.push(test.masking);
+        bmhd.push(test.compression);
+        bmhd.push(0);
+        bmhd.extend_from_slice(&test.transparent.to_be_bytes());
+        bmhd.extend_from_slice(&[10, 11]);
+        bmhd.extend_from_slice(&(test.width as i16).to_be_bytes());
+        bmhd.extend_from_slice(&(test.height as i16).to_be_bytes());
+
+        let row_stride = test.width.div_ceil(16) * 2;
+        let mut body = Vec::new();
+        for y in 0..test.height {
+            let values = &test.pixels[y * test.width..(y + 1) * test.width];
+            let mut rows: Vec<Vec<u8>> = if test.chunky {
+                let mut row: Vec<u8> = values.iter().map(|&v| v as u8).collect();
+                row.resize(test.width + (test.width & 1), 0);
+                vec![row]
+            } else {
+                (0..test.planes)
+                    .map(|plane| plane_row(values, 1 << plane, row_stride))
+                    .collect()
+            };
+
+            if test.masking == 1 {
+                let mask: Vec<u32> = test.mask[y * test.width..(y + 1) * test.width]
+                    .iter()
+                    .map(|&m| m as u32)
+                    .collect();
+                rows.push(plane_row(&mask, 1, row_stride));
+            }
+
+            for row in rows {
+                if test.compression != 1 {
+                    body.extend_from_slice(&row);
+                } else {
+                    pack_literal(&row, &mut body);
+                }
+            }
+        }
+
+        if test.contiguous || test.compression == 2 {
+            // Gather up each plane (and the mask) from the rows
+            let planes = test.planes + (test.masking == 1) as usize;
+            body = (0..planes)
+                .flat_map(|plane| body.chunks(row_stride).skip(plane).step_by(planes))
+                .flatten()
+                .copied()
+                .collect();
+        }
+
+        if test.compression == 2 {
+            // Each plane in a VDAT, as a single literal of all its words, down each column
+            let plane_size = row_stride * test.height;
+            body = body.chunks(plane_size).fold(Vec::new(), |mut vdats, plane| {
+                let mut vdat = vec![0, 3, 0];
+                vdat.extend_from_slice(&((plane_size / 2) as u16).to_be_bytes());
+                for column in (0..row_stride).step_by(2) {
+                    for y in 0..test.height {
+                        vdat.extend_from_slice(&plane[y * row_stride + column..][..2]);
+                    }
+                }
+                chunk(b"VDAT", &vdat, &mut vdats);
+                vdats
+            });
+        }
+
+        let mut form = if test.chunky {
+            b"PBM ".to_vec()
+        } else if test.contiguous {
+            b"ACBM".to_vec()
+        } else {
+            b"ILBM".to_vec()
+        };
+        chunk(b"BMHD", &bmhd, &mut form);
+        if let Some(cmap) = &test.cmap {
+            chunk(b"CMAP", cmap, &mut form);
+        }
+        if let Some(camg) = test.camg {
+            chunk(b"CAMG", &camg.to_be_bytes(), &mut form);
+        }
+        let body_id = if test.contiguous { b"ABIT" } else { b"BODY" };
+        chunk(body_id, &body, &mut form);
+
+        let mut file = Vec::new();
+        chunk(b"FORM", &form, &mut file);
+        file
+    }
+
+    /// Slip extra chunks in at the start of the FORM, fixing up its length
+    pub(crate) fn insert_chunks(mut bytes: Vec<u8>, chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut extra = Vec::new();
+        for (id, data) in chunks {
+            chunk(id, data, &mut extra);
+        }
+        bytes.splice(12..12, extra);
+        let len = (bytes.len() - 8) as u32;
+        bytes[4..8].copy_from_slice(&len.to_be_bytes());
+        bytes
+    }
+
+    pub(crate) fn read_test(bytes: &[u8], pixel_format: PixelFormat) -> Result<IlbmImage> {
+        read_from_bytes(
+            bytes,
+            ReadOptions {
+                pixel_format,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// A 4 color palette, black, red, green and blue
+    fn four_colors() -> Vec<u8> {
+        vec![0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0, 255]
+    }
+
+    fn masked_image(compression: u8) -> TestImage {
+        // Wider than 16 pixels so rows span more than one word
+        let width = 20;
+        let height = 3;
+        TestImage {
+            width,
+            height,
+            planes: 2,
+            masking: 1,
+            compression,
+            cmap: Some(four_colors()),
+            pixels: (0..width * height).map(|i| (i % 4) as u32).collect(),
+            mask: (0..width * height).map(|i| i % 3 != 0).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn check_masked(image: &IlbmImage, test: &TestImage) {
+        let cmap = test.cmap.as_ref().unwrap();
+        assert_eq!(image.masking, Masking::HasMask);
+        assert_eq!(image.pixel_format, PixelFormat::Rgba);
+        assert_eq!(image.pixels.len(), test.width * test.height * 4);
+
+        for (i, pixel) in image.pixels.chunks(4).enumerate() {
+            let index = test.pixels[i] as usize * 3;
+            assert_eq!(&pixel[..3], &cmap[index..index + 3]);
+            assert_eq!(pixel[3], if test.mask[i] { 255 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn mask_uncompressed() {
+        let test = masked_image(0);
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgba).unwrap();
+        check_masked(&image, &test);
+    }
+
+    #[test]
+    fn mask_compressed() {
+        let test = masked_image(1);
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgba).unwrap();
+        check_masked(&image, &test);
+    }
+
+    #[test]
+    fn mask_deep() {
+        let width = 5;
+        let height = 2;
+        let test = TestImage {
+            width,
+            height,
+            planes: 24,
+            masking: 1,
+            compression: 1,
+            pixels: (0..width * height).map(|i| 0x102030 * i as u32).collect(),
+            mask: (0..width * height).map(|i| i % 2 == 0).collect(),
+            ..Default::default()
+        };
+
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgba).unwrap();
+
+        for (i, pixel) in image.pixels.chunks(4).enumerate() {
+            let value = test.pixels[i];
+            let expected_alpha = if test.mask[i] { 255 } else { 0 };
+            assert_eq!(
+                pixel,
+                &[value as u8, (value >> 8) as u8, (value >> 16) as u8, expected_alpha]
+            );
+        }
+    }
+
+    /// A deep image with every channel value, and without a color map
+    fn deep_image(planes: usize, masking: u8) -> TestImage {
+        let width = 9;
+        let height = 3;
+        TestImage {
+            width,
+            height,
+            planes,
+            masking,
+            compression: 1,
+            pixels: (0..width * height).map(|i| 0x9e3779b9u32.wrapping_mul(i as u32 + 1)).collect(),
+            mask: (0..width * height).map(|i| i % 4 != 1).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn deep_plane_counts() {
+        for planes in [12, 18, 24] {
+            let bits = planes / 3;
+            let mut test = deep_image(planes, 0);
+            for p in test.pixels.iter_mut() {
+                *p &= (1 << planes) - 1;
+            }
+
+            let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgb).unwrap();
+            assert_eq!(image.planes, planes);
+
+            let max = (1 << bits) - 1;
+            for (i, pixel) in image.pixels.chunks(3).enumerate() {
+                for (c, &value) in pixel.iter().enumerate() {
+                    let channel = (test.pixels[i] >> (c * bits)) & max;
+                    // Scaled so that the extremes are black and full color
+                    let expected = (channel as f64 * 255.0 / max as f64).round() as u8;
+                    assert_eq!(value, expected, "{} planes, pixel {} channel {}", planes, i, c);
+                }
+            }
+        }
+
+        // The extremes map to 0 and 255
+        assert_eq!(super::scale_channel(0, 4), 0);
+        assert_eq!(super::scale_channel(15, 4), 255);
+        assert_eq!(super::scale_channel(63, 6), 255);
+        assert_eq!(super::scale_channel(0x8, 4), 0x88);
+    }
+
+    #[test]
+    fn deep_alpha() {
+        let test = deep_image(32, 0);
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgba).unwrap();
+        let expected: Vec<u8> = test
+            .pixels
+            .iter()
+            .flat_map(|p| {
+                let [a, b, g, r] = p.to_be_bytes();
+                [r, g, b, a]
+            })
+            .collect();
+        assert_eq!(image.pixels, expected);
+
+        // RGB drops the alpha
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgb).unwrap();
+        let rgb: Vec<u8> = expected.chunks(4).flat_map(|p| p[..3].to_vec()).collect();
+        assert_eq!(image.pixels, rgb);
+
+        // A mask as well makes pixels transparent, whatever their alpha
+        let test = deep_image(32, 1);
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgba).unwrap();
+        for (i, pixel) in image.pixels.chunks(4).enumerate() {
+            let alpha = if test.mask[i] { (test.pixels[i] >> 24) as u8 } else { 0 };
+            assert_eq!(pixel[3], alpha, "alpha of pixel {}", i);
+        }
+    }
+
+    #[test]
+    fn deep_not_supported() {
+        for planes in [3, 6, 9, 16, 20, 21, 25] {
+            let test = deep_image(planes, 0);
+            assert!(
+                matches!(
+                    read_test(&ilbm_bytes(&test), PixelFormat::Rgb),
+                    Err(IlbmError::NotSupported(_))
+                ),
+                "{} planes",
+                planes
+            );
+        }
+    }
+
+    #[test]
+    fn ham_and_halfbrite_need_color_map() {
+        for camg in [0x800, 0x80] {
+            let test = TestImage {
+                cmap: None,
+                ..transparent_image(camg, 6)
+            };
+            assert!(matches!(
+                read_test(&ilbm_bytes(&test), PixelFormat::Rgb),
+                Err(IlbmError::InvalidData(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn byte_run2() {
+        let test = TestImage {
+            compression: 2,
+            ..masked_image(0)
+        };
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgba).unwrap();
+        assert_eq!(image.compression, Compression::ByteRun2);
+        check_masked(&image, &test);
+
+        // A missing plane
+        let mut bytes = ilbm_bytes(&TestImage {
+            compression: 2,
+            masking: 0,
+            ..masked_image(0)
+        });
+        let vdat = bytes.windows(4).rposition(|w| w == b"VDAT").unwrap();
+        bytes[vdat..vdat + 4].copy_from_slice(b"XDAT");
+        assert!(matches!(
+            read_test(&bytes, PixelFormat::Rgb),
+            Err(IlbmError::InvalidData(_))
+        ));
+    }
+
+    #[test]
+    fn unknown_compression() {
+        let test = TestImage {
+            compression: 3,
+            ..masked_image(0)
+        };
+        assert!(matches!(
+            read_test(&ilbm_bytes(&test), PixelFormat::Rgb),
+            Err(IlbmError::NotSupported(_))
+        ));
+
+        // But the header can still be looked at
+        let probed = probe_from_reader(std::io::Cursor::new(ilbm_bytes(&test)), ReadOptions::default()).unwrap();
+        assert_eq!(probed.compression, Compression::NotSupported(3));
+        assert_eq!(probed.size.width(), test.width);
+        let header = read_from_bytes(
+            &ilbm_bytes(&test),
+            ReadOptions {
+                read_pixels: false,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(header.compression, Compression::NotSupported(3));
+
+        // ByteRun2 is only for planes
+        let test = TestImage {
+            compression: 2,
+            ..pbm_image(0)
+        };
+        assert!(matches!(
+            read_test(&ilbm_bytes(&test), PixelFormat::Rgb),
+            Err(IlbmError::NotSupported(_))
+        ));
+    }
+
+    #[test]
+    fn mask_dropped_for_rgb() {
+        let test = masked_image(1);
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgb).unwrap();
+        assert_eq!(image.pixels.len(), test.width * test.height * 3);
+        assert_eq!(&image.pixels[3..6], &[255, 0, 0]);
+    }
+
+    #[test]
+    fn no_mask_is_opaque() {
+        let test = TestImage {
+            masking: 0,
+            ..masked_image(0)
+        };
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgba).unwrap();
+        assert!(image.pixels.chunks(4).all(|pixel| pixel[3] == 255));
+    }
+
+    #[test]
+    fn missing_mask_plane() {
+        let mut test = masked_image(0);
+        test.masking = 0;
+        let mut bytes = ilbm_bytes(&test);
+
+        // Claim there is a mask, but there is no data for it
+        let masking_offset = 12 + 8 + 9;
+        bytes[masking_offset] = 1;
+
+        match read_test(&bytes, PixelFormat::Rgba) {
+            Err(IlbmError::NoData) => (),
+            other => panic!("Expected NoData, got {:?}", other.map(|i| i.to_string())),
+        }
+    }
+
+    fn transparent_image(camg: u32, planes: usize) -> TestImage {
+        let width = 18;
+        let height = 2;
+        TestImage {
+            width,
+            height,
+            planes,
+            masking: 2,
+            transparent: 5,
+            cmap: Some((0..32 * 3).map(|i| (i * 8) as u8).collect()),
+            camg: Some(camg),
+            pixels: (0..width * height).map(|i| (i % 12) as u32).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn check_transparent(image: &IlbmImage, test: &TestImage) {
+        assert_eq!(image.masking, Masking::HasTransparentColor);
+        for (i, pixel) in image.pixels.chunks(4).enumerate() {
+            let expected = if test.pixels[i] == 5 { 0 } else { 255 };
+            assert_eq!(pixel[3], expected, "alpha of pixel {}", i);
+        }
+    }
+
+    #[test]
+    fn transparent_color() {
+        let test = transparent_image(0, 4);
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgba).unwrap();
+        check_transparent(&image, &test);
+    }
+
+    #[test]
+    fn transparent_color_ham() {
+        // Index 5 in HAM6 is an unmodified palette entry, index 0x25 would
+        // modify red by 5, but only the raw value counts
+        let mut test = transparent_image(0x800, 6);
+        test.pixels[3] = 0x25;
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgba).unwrap();
+        check_transparent(&image, &test);
+    }
+
+    #[test]
+    fn transparent_color_halfbrite() {
+        let mut test = transparent_image(0x80, 6);
+        test.pixels[3] = 0x25;
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgba).unwrap();
+        check_transparent(&image, &test);
+
+        // The half bright pixel is not transparent, but is darker
+        assert_eq!(&image.pixels[12..16], &[60, 64, 68, 255]);
+    }
+
+    /// Two rows of HAM, with a palette of (10 + i, 20 + i, 30 + i)
+    fn ham_image(planes: usize, map_size: usize, pixels: &[u32]) -> TestImage {
+        TestImage {
+            width: 5,
+            height: 2,
+            planes,
+            cmap: Some((0..map_size).flat_map(|i| [10 + i as u8, 20 + i as u8, 30 + i as u8]).collect()),
+            camg: Some(0x800),
+            pixels: pixels.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[rustfmt::skip]
+    const HAM_RENDER: [u8; 30] = [
+        // Modify red, green and blue of the border color, a palette entry, then red again
+        17, 20, 30,   17, 255, 30,   17, 255, 136,   13, 23, 33,   170, 23, 33,
+        // The second row starts again from the border color
+        10, 20, 255,   10, 20, 30,   10, 85, 30,   25, 35, 45,   0, 35, 45,
+    ];
+
+    #[test]
+    fn ham6() {
+        let test = ham_image(6, 16, &[0x21, 0x3f, 0x18, 0x03, 0x2a, 0x1f, 0x00, 0x35, 0x0f, 0x20]);
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgb).unwrap();
+        assert_eq!(image.pixels, HAM_RENDER);
+    }
+
+    #[test]
+    fn ham8() {
+        // 6 bit components have their top 2 bits repeated at the bottom, so the
+        // blue of the first row is a little different
+        let test = ham_image(8, 64, &[0x81, 0xff, 0x60, 0x03, 0xaa, 0x7f, 0x00, 0xd5, 0x0f, 0x80]);
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgb).unwrap();
+
+        let mut expected = HAM_RENDER;
+        expected[0] = 4;
+        expected[3] = 4;
+        expected[6] = 4;
+        expected[8] = 130;
+        assert_eq!(image.pixels, expected);
+    }
+
+    #[test]
+    fn ham_short_palette() {
+        // HAM8 with only 16 colors is fine, as long as only they are used
+        let pixels = [0x81, 0xff, 0x60, 0x03, 0xaa, 0x7f, 0x00, 0xd5, 0x0f, 0x80];
+        let test = ham_image(8, 16, &pixels);
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgb).unwrap();
+        assert_eq!(&image.pixels[9..12], &[13, 23, 33]);
+
+        let mut test = ham_image(8, 16, &pixels);
+        test.pixels[3] = 0x20;
+        assert!(matches!(
+            read_test(&ilbm_bytes(&test), PixelFormat::Rgb),
+            Err(IlbmError::NoMapEntry { index: 0x20, map_size: 16 })
+        ));
+
+        // HAM6 with only 4 colors
+        let test = ham_image(6, 4, &[0x21, 0x3f, 0x18, 0x03, 0x2a, 0x1f, 0x00, 0x35, 0x02, 0x20]);
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgb).unwrap();
+        assert_eq!(&image.pixels[..12], &HAM_RENDER[..12]);
+        assert_eq!(&image.pixels[24..27], &[12, 22, 32]);
+    }
+
+    #[test]
+    fn ham_plane_counts() {
+        for planes in [1, 3, 5, 7] {
+            let test = ham_image(planes, 2, &[0, 1, 0, 1, 0, 1, 0, 1, 0, 1]);
+            assert!(
+                matches!(
+                    read_test(&ilbm_bytes(&test), PixelFormat::Rgb),
+                    Err(IlbmError::NotSupported(_))
+                ),
+                "{} planes",
+                planes
+            );
+        }
+    }
+
+    #[test]
+    fn ham_and_halfbrite() {
+        // Halfbrite is ignored, even for HAM8 where it could never work
+        let mut test = ham_image(6, 16, &[0x21, 0x3f, 0x18, 0x03, 0x2a, 0x1f, 0x00, 0x35, 0x0f, 0x20]);
+        test.camg = Some(0x880);
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgb).unwrap();
+        assert_eq!(image.display_mode.value(), 0x880);
+        assert!(image.display_mode.effective().is_ham());
+        assert!(!image.display_mode.effective().is_halfbrite());
+        assert_eq!(image.pixels, HAM_RENDER);
+
+        let mut test = ham_image(8, 64, &[0x81, 0xff, 0x60, 0x03, 0xaa, 0x7f, 0x00, 0xd5, 0x0f, 0x80]);
+        test.camg = Some(0x880);
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgb).unwrap();
+        assert_eq!(image.display_mode.value(), 0x880);
+        assert!(!image.display_mode.effective().is_halfbrite());
+        assert_eq!(&image.pixels[15..], &HAM_RENDER[15..]);
+    }
+
+    #[test]
+    fn ham_bogus_camg() {
+        // Junk in the upper word, as some brushes have, is treated like no CAMG at all,
+        // so a 6 plane, 16 color image is still HAM6
+        let mut test = ham_image(6, 16, &[0x21, 0x3f, 0x18, 0x03, 0x2a, 0x1f, 0x00, 0x35, 0x0f, 0x20]);
+        test.camg = Some(0xDEAD_0800);
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgb).unwrap();
+        assert!(image.display_mode.is_ham());
+        assert_eq!(image.pixels, HAM_RENDER);
+
+        let probed = probe_from_reader(std::io::Cursor::new(ilbm_bytes(&test)), ReadOptions::default()).unwrap();
+        assert!(probed.display_mode.is_ham());
+    }
+
+    /// A halfbrite image, using a normal color, its darker twin and an unused one
+    fn halfbrite_image(planes: usize, map_size: usize) -> TestImage {
+        let mut cmap: Vec<u8> = (0..map_size.min(32) * 3).map(|i| (i * 8) as u8).collect();
+        cmap.resize(map_size * 3, 7);
+        TestImage {
+            width: 3,
+            height: 1,
+            planes,
+            cmap: Some(cmap),
+            camg: Some(0x80),
+            pixels: vec![0x05, 0x25, 0x14],
+            ..Default::default()
+        }
+    }
+
+    fn read_halfbrite(test: &TestImage, pixel_format: PixelFormat, halfbrite: HalfbritePolicy) -> IlbmImage {
+        let options = ReadOptions {
+            pixel_format,
+            halfbrite,
+            ..Default::default()
+        };
+        read_from_bytes(&ilbm_bytes(test), options).unwrap()
+    }
+
+    #[test]
+    fn halfbrite_derive() {
+        // The stored upper half is ignored, it would be all 7s
+        let test = halfbrite_image(6, 64);
+        let image = read_halfbrite(&test, PixelFormat::Rgb, HalfbritePolicy::Derive);
+        assert_eq!(image.pixels, [120, 128, 136, 60, 64, 68, 224, 232, 240]);
+
+        let image = read_halfbrite(&test, PixelFormat::Indexed, HalfbritePolicy::Derive);
+        let map = image.color_map.unwrap();
+        assert_eq!(map.len(), 64);
+        assert_eq!(map.colors()[0x25], RgbValue(60, 64, 68));
+        assert_eq!(map.original_colors()[0x25], RgbValue(7, 7, 7));
+    }
+
+    #[test]
+    fn halfbrite_trust_stored() {
+        let test = halfbrite_image(6, 64);
+        let image = read_halfbrite(&test, PixelFormat::Rgb, HalfbritePolicy::TrustStored);
+        assert_eq!(image.pixels, [120, 128, 136, 7, 7, 7, 224, 232, 240]);
+
+        let image = read_halfbrite(&test, PixelFormat::Indexed, HalfbritePolicy::TrustStored);
+        assert_eq!(image.color_map.unwrap().colors()[0x25], RgbValue(7, 7, 7));
+
+        // With only 32 stored, there is nothing to trust, so it is derived
+        let test = halfbrite_image(6, 32);
+        let image = read_halfbrite(&test, PixelFormat::Rgb, HalfbritePolicy::TrustStored);
+        assert_eq!(image.pixels, [120, 128, 136, 60, 64, 68, 224, 232, 240]);
+    }
+
+    #[test]
+    fn halfbrite_short_palette() {
+        // Colors missing from the lower half are black, and so are their twins
+        let mut test = halfbrite_image(6, 16);
+        test.pixels = vec![0x05, 0x25, 0x34];
+        for policy in [HalfbritePolicy::Derive, HalfbritePolicy::TrustStored] {
+            let image = read_halfbrite(&test, PixelFormat::Rgb, policy);
+            assert_eq!(image.pixels, [120, 128, 136, 60, 64, 68, 0, 0, 0]);
+
+            let image = read_halfbrite(&test, PixelFormat::Indexed, policy);
+            assert_eq!(image.pixels, [0x05, 0x25, 0x34]);
+            assert_eq!(image.color_map.unwrap().len(), 64);
+        }
+    }
+
+    #[test]
+    fn halfbrite_planes() {
+        // With 5 planes, there are simply no darker pixels
+        let mut test = halfbrite_image(5, 32);
+        test.pixels = vec![0x05, 0x1f, 0x14];
+        let image = read_halfbrite(&test, PixelFormat::Rgb, HalfbritePolicy::Derive);
+        assert_eq!(&image.pixels[..3], &[120, 128, 136]);
+        assert_eq!(&image.pixels[3..6], &[232, 240, 248]);
+
+        let test = halfbrite_image(7, 64);
+        assert!(matches!(
+            read_test(&ilbm_bytes(&test), PixelFormat::Rgb),
+            Err(IlbmError::NotSupported(_))
+        ));
+    }
+
+    #[test]
+    fn lasso() {
+        // A ring of color 1 on a background of color 0, with color 0
+        // inside the ring, which must stay opaque
+        #[rustfmt::skip]
+        let pixels = vec![
+            0, 0, 0, 0, 0, 0,
+            0, 1, 1, 1, 1, 0,
+            0, 1, 0, 0, 1, 0,
+            0, 1, 1, 1, 1, 0,
+            0, 0, 0, 0, 2, 0,
+        ];
+        #[rustfmt::skip]
+        let expected = vec![
+            0,   0,   0,   0,   0,   0,
+            0,   255, 255, 255, 255, 0,
+            0,   255, 255, 255, 255, 0,
+            0,   255, 255, 255, 255, 0,
+            0,   0,   0,   0,   255, 0,
+        ];
+
+        let test = TestImage {
+            width: 6,
+            height: 5,
+            planes: 2,
+            masking: 3,
+            compression: 1,
+            transparent: 0,
+            cmap: Some(four_colors()),
+            pixels,
+            ..Default::default()
+        };
+
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgba).unwrap();
+        assert_eq!(image.masking, Masking::Lasso);
+
+        let alpha: Vec<u8> = image.pixels.chunks(4).map(|pixel| pixel[3]).collect();
+        assert_eq!(alpha, expected);
+    }
+
+    #[test]
+    fn lasso_no_border() {
+        // Transparent color not on the border, so nothing is outside the lasso
+        let test = TestImage {
+            width: 3,
+            height: 3,
+            planes: 2,
+            masking: 3,
+            transparent: 0,
+            cmap: Some(four_colors()),
+            pixels: vec![1, 1, 1, 1, 0, 1, 1, 1, 1],
+            ..Default::default()
+        };
+
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgba).unwrap();
+        assert!(image.pixels.chunks(4).all(|pixel| pixel[3] == 255));
+    }
+
+    #[test]
+    fn indexed() {
+        let test = masked_image(1);
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Indexed).unwrap();
+
+        assert_eq!(image.pixel_format, PixelFormat::Indexed);
+        let expected: Vec<u8> = test.pixels.iter().map(|&p| p as u8).collect();
+        assert_eq!(image.pixels, expected);
+
+        let map = image.color_map.unwrap();
+        assert_eq!(map.len(), 4);
+        assert_eq!(map.colors()[1], RgbValue(255, 0, 0));
+    }
+
+    fn pbm_image(compression: u8) -> TestImage {
+        // An odd width, so every row has a pad byte
+        let width = 13;
+        let height = 3;
+        TestImage {
+            width,
+            height,
+            planes: 8,
+            compression,
+            cmap: Some((0..256 * 3).map(|i| (i % 256) as u8).collect()),
+            pixels: (0..width * height).map(|i| (i * 7 % 256) as u32).collect(),
+            chunky: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn pbm() {
+        for compression in [0, 1] {
+            let test = pbm_image(compression);
+            let bytes = ilbm_bytes(&test);
+
+            let image = read_test(&bytes, PixelFormat::Indexed).unwrap();
+            let expected: Vec<u8> = test.pixels.iter().map(|&p| p as u8).collect();
+            assert_eq!(image.pixels, expected);
+
+            let image = read_test(&bytes, PixelFormat::Rgb).unwrap();
+            assert_eq!((image.size.width(), image.size.height()), (13, 3));
+            for (i, pixel) in image.pixels.chunks(3).enumerate() {
+                let p = test.pixels[i] as usize;
+                let expected = [(p * 3) as u8, (p * 3 + 1) as u8, (p * 3 + 2) as u8];
+                assert_eq!(pixel, expected, "pixel {}", i);
+            }
+        }
+    }
+
+    /// Claim the biggest image a BMHD can hold, for a file with a tiny body,
+    /// which must be an error, not an attempt to make room for billions of pixels
+    fn claim_huge(bytes: &mut [u8]) {
+        let size_offset = 12 + 8;
+        bytes[size_offset..size_offset + 4].copy_from_slice(&[0xff; 4]);
+    }
+
+    #[test]
+    fn pbm_huge() {
+        for compression in [0, 1] {
+            let mut bytes = ilbm_bytes(&pbm_image(compression));
+            claim_huge(&mut bytes);
+
+            for pixel_format in [PixelFormat::Rgb, PixelFormat::Rgba, PixelFormat::Indexed] {
+                assert!(matches!(read_test(&bytes, pixel_format), Err(IlbmError::NoData)));
+            }
+        }
+    }
+
+    #[test]
+    fn pbm_transparent_color() {
+        let test = TestImage {
+            masking: 2,
+            transparent: 7,
+            ..pbm_image(1)
+        };
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgba).unwrap();
+        for (i, pixel) in image.pixels.chunks(4).enumerate() {
+            let expected = if test.pixels[i] == 7 { 0 } else { 255 };
+            assert_eq!(pixel[3], expected, "alpha of pixel {}", i);
+        }
+    }
+
+    #[test]
+    fn pbm_needs_color_map() {
+        let test = TestImage {
+            cmap: None,
+            ..pbm_image(0)
+        };
+        let result = read_test(&ilbm_bytes(&test), PixelFormat::Rgb);
+        assert!(matches!(result, Err(IlbmError::NotSupported(_))));
+
+        // The header alone is fine
+        let bytes = ilbm_bytes(&test);
+        let image = probe_from_reader(std::io::Cursor::new(bytes), ReadOptions::default()).unwrap();
+        assert_eq!((image.size.width(), image.size.height()), (13, 3));
+    }
+
+    #[test]
+    fn acbm() {
+        let test = TestImage {
+            contiguous: true,
+            ..masked_image(0)
+        };
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgba).unwrap();
+        check_masked(&image, &test);
+
+        let test = TestImage {
+            masking: 0,
+            ..test
+        };
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Indexed).unwrap();
+        let expected: Vec<u8> = test.pixels.iter().map(|&p| p as u8).collect();
+        assert_eq!(image.pixels, expected);
+    }
+
+    #[test]
+    fn acbm_display_modes() {
+        // The same pixels should come out whichever way the planes are stored
+        for (camg, planes) in [(0x800, 6), (0x80, 6)] {
+            let test = transparent_image(camg, planes);
+            let ilbm = read_test(&ilbm_bytes(&test), PixelFormat::Rgb).unwrap();
+
+            let test = TestImage {
+                contiguous: true,
+                ..test
+            };
+            let acbm = read_test(&ilbm_bytes(&test), PixelFormat::Rgb).unwrap();
+            assert_eq!(acbm.display_mode.value(), camg);
+            assert_eq!(acbm.pixels, ilbm.pixels);
+        }
+    }
+
+    #[test]
+    fn acbm_too_short() {
+        let test = TestImage {
+            contiguous: true,
+            ..masked_image(0)
+        };
+        let mut bytes = ilbm_bytes(&test);
+
+        // Claim an extra row, which the ABIT has no room for
+        let height_offset = 12 + 8 + 2;
+        bytes[height_offset + 1] += 1;
+
+        let result = read_test(&bytes, PixelFormat::Rgba);
+        assert!(matches!(result, Err(IlbmError::InvalidData(_))));
+    }
+
+    #[test]
+    fn acbm_huge() {
+        let test = TestImage {
+            contiguous: true,
+            ..masked_image(0)
+        };
+        let mut bytes = ilbm_bytes(&test);
+        claim_huge(&mut bytes);
+
+        let result = read_test(&bytes, PixelFormat::Rgba);
+        assert!(matches!(result, Err(IlbmError::InvalidData(_))));
+    }
+
+    /// An Impulse image, with the header of a 13 plane RGBN, or a 25 plane RGB8
+    fn impulse_bytes(form_type: &[u8; 4], width: u16, height: u16, body: &[u8]) -> Vec<u8> {
+        let planes = if form_type == b"RGBN" { 13 } else { 25 };
+
+        let mut bmhd = Vec::new();
+        bmhd.extend_from_slice(&width.to_be_bytes());
+        bmhd.extend_from_slice(&height.to_be_bytes());
+        bmhd.extend_from_slice(&[0, 0, 0, 0, planes, 0, 4, 0, 0, 0, 1, 1]);
+        bmhd.extend_from_slice(&width.to_be_bytes());
+        bmhd.extend_from_slice(&height.to_be_bytes());
+
+        let mut form = form_type.to_vec();
+        chunk(b"BMHD", &bmhd, &mut form);
+        chunk(b"BODY", body, &mut form);
+
+        let mut file = Vec::new();
+        chunk(b"FORM", &form, &mut file);
+        file
+    }
+
+    #[test]
+    fn rgbn() {
+        let body = [
+            // 3 of orange
+            0xf8, 0x03, //
+            // 2 of genlocked blue, running over into the next row
+            0x00, 0xfa, //
+            // A count of zero, followed by a byte count of 3, of grey
+            0x88, 0x80, 3, //
+            // A count of zero, and a byte of zero, followed by a word count, of white
+            0xff, 0xf0, 0, 0, 4,
+        ];
+        let bytes = impulse_bytes(b"RGBN", 4, 3, &body);
+
+        let image = read_test(&bytes, PixelFormat::Rgba).unwrap();
+        let orange = [255, 136, 0, 255];
+        let blue = [0, 0, 255, 0];
+        let grey = [136, 136, 136, 255];
+        let white = [255, 255, 255, 255];
+        let expected: Vec<u8> = [orange, orange, orange, blue, blue, grey, grey, grey]
+            .iter()
+            .chain([white; 4].iter())
+            .flatten()
+            .copied()
+            .collect();
+        assert_eq!(image.pixels, expected);
+
+        // Without alpha the genlock is just dropped
+        let image = read_test(&bytes, PixelFormat::Rgb).unwrap();
+        assert_eq!(image.pixels.len(), 4 * 3 * 3);
+        assert_eq!(&image.pixels[9..12], &[0, 0, 255]);
+
+        assert!(matches!(
+            read_test(&bytes, PixelFormat::Indexed),
+            Err(IlbmError::NotSupported(_))
+        ));
+    }
+
+    #[test]
+    fn rgb8() {
+        let body = [
+            10, 20, 30, 5, //
+            40, 50, 60, 0x80 | 2, //
+            70, 80, 90, 0, 1, //
+        ];
+        let bytes = impulse_bytes(b"RGB8", 3, 2, &body);
+
+        let image = read_test(&bytes, PixelFormat::Rgba).unwrap();
+        assert_eq!(
+            image.pixels,
+            [
+                10, 20, 30, 255, 10, 20, 30, 255, 10, 20, 30, 255, //
+                10, 20, 30, 255, 10, 20, 30, 255, 40, 50, 60, 0,
+            ]
+        );
+
+        // Runs past the end of the image are cut off, too little data is an error
+        let bytes = impulse_bytes(b"RGB8", 3, 2, &body[..6]);
+        assert!(matches!(
+            read_test(&bytes, PixelFormat::Rgb),
+            Err(IlbmError::NoData)
+        ));
+    }
+
+    #[test]
+    fn impulse_huge() {
+        let bytes = impulse_bytes(b"RGBN", 0xffff, 0xffff, &[0xf8, 0x03]);
+        assert!(matches!(read_test(&bytes, PixelFormat::Rgba), Err(IlbmError::NoData)));
+
+        let bytes = impulse_bytes(b"RGB8", 0xffff, 0xffff, &[10, 20, 30, 5]);
+        assert!(matches!(read_test(&bytes, PixelFormat::Rgb), Err(IlbmError::NoData)));
+    }
+
+    #[test]
+    fn indexed_halfbrite() {
+        let mut test = transparent_image(0x80, 6);
+        test.pixels[3] = 0x25;
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Indexed).unwrap();
+
+        assert_eq!(image.pixels[3], 0x25);
+
+        let map = image.color_map.unwrap();
+        assert_eq!(map.len(), 64);
+        assert_eq!(map.colors()[0x25], RgbValue(60, 64, 68));
+    }
+
+    #[test]
+    fn indexed_not_supported() {
+        let ham = transparent_image(0x800, 6);
+        assert!(matches!(
+            read_test(&ilbm_bytes(&ham), PixelFormat::Indexed),
+            Err(IlbmError::NotSupported(_))
+        ));
+
+        let deep = TestImage {
+            width: 2,
+            height: 2,
+            planes: 24,
+            pixels: vec![0; 4],
+            ..Default::default()
+        };
+        assert!(matches!(
+            read_test(&ilbm_bytes(&deep), PixelFormat::Indexed),
+            Err(IlbmError::NotSupported(_))
+        ));
+    }
+
+    #[test]
+    fn color_map() {
+        let test = masked_image(0);
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgb).unwrap();
+
+        let map = image.color_map.unwrap();
+        assert!(!map.is_fixed_up());
+        assert_eq!(map.colors(), map.original_colors());
+        assert_eq!(map.colors()[3].blue(), 255);
+    }
+
+    #[test]
+    fn old_color_map() {
+        let mut test = masked_image(0);
+        test.cmap = Some(vec![0, 0, 0, 0xf0, 0, 0, 0, 0x80, 0, 0, 0, 0x10]);
+        let image = read_test(&ilbm_bytes(&test), PixelFormat::Rgb).unwrap();
+
+        let map = image.color_map.unwrap();
+        assert!(map.is_fixed_up());
+        assert_eq!(map.colors()[1], RgbValue::new(0xff, 0, 0));
+        assert_eq!(map.colors()[2], RgbValue::new(0, 0x88, 0));
+        assert_eq!(map.colors()[3], RgbValue::new(0, 0, 0x11));
+        assert_eq!(map.original_colors()[1], RgbValue::new(0xf0, 0, 0));
+        assert_eq!(map.original_colors()[2].green(), 0x80);
+
+        // and the pixels use the fixed up colors
+        assert_eq!(&image.pixels[3..6], &[0xff, 0, 0]);
+    }
+
+    #[test]
+    fn file_bytes_and_reader_agree() {
+        let test = masked_image(1);
+        let bytes = ilbm_bytes(&test);
+
+        let path = std::env::temp_dir().join(format!("ilbm_read_test_{}.iff", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+
+        for &pixel_format in &[PixelFormat::Rgb, PixelFormat::Rgba, PixelFormat::Indexed] {
+            let options = || ReadOptions {
+                pixel_format,
+                page_scale: true,
+                ..Default::default()
+            };
+
+            let from_file = read_from_file(&path, options()).unwrap();
+            let from_bytes = read_from_bytes(&bytes, options()).unwrap();
+            let from_reader = read_from_reader(std::io::Cursor::new(&bytes), options()).unwrap();
+
+            for image in &[&from_bytes, &from_reader] {
+                assert_eq!(image.pixels, from_file.pixels);
+                assert_eq!(image.to_string(), from_file.to_string());
+            }
+        }
+
+        std::fs::remove_file(&path).unwrap();
+
+        // and they all fail the same way
+        let truncated = &bytes[..bytes.len() / 2];
+        assert!(read_from_bytes(truncated, ReadOptions::default()).is_err());
+        assert!(read_from_reader(truncated, ReadOptions::default()).is_err());
+    }
+
+    /// Counts the bytes actually read, to prove probing skips the BODY
+    struct CountingReader<'a> {
+        inner: std::io::Cursor<&'a [u8]>,
+        count: usize,
+    }
+
+    impl<'a> std::io::Read for CountingReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.count += n;
+            Ok(n)
+        }
+    }
+
+    impl<'a> std::io::Seek for CountingReader<'a> {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn probe() {
+        let (width, height) = (320, 200);
+        let test = TestImage {
+            width,
+            height,
+            planes: 24,
+            camg: Some(0x8004),
+            pixels: vec![0x123456; width * height],
+            ..Default::default()
+        };
+        let bytes = ilbm_bytes(&test);
+
+        let mut reader = CountingReader {
+            inner: std::io::Cursor::new(&bytes),
+            count: 0,
+        };
+        let probed = probe_from_reader(&mut reader, ReadOptions::default()).unwrap();
+        let read = read_from_bytes(
+            &bytes,
+            ReadOptions {
+                read_pixels: false,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(probed.to_string(), read.to_string());
+        assert!(probed.pixels.is_empty());
+        assert!(reader.count < 100, "read {} bytes", reader.count);
+    }
+
+    #[test]
+    fn probe_matches_read() {
+        for test in &[masked_image(1), transparent_image(0x800, 6), transparent_image(0x80, 6)] {
+            let bytes = ilbm_bytes(test);
+
+            // Some other form first, which must be skipped
+            let mut file = Vec::new();
+            file.extend_from_slice(b"FORM\0\0\0\x058SVXx\0");
+            file.extend_from_slice(&bytes);
+
+            let options = || ReadOptions {
+                read_pixels: false,
+                page_scale: true,
+                ..Default::default()
+            };
+            let probed = probe_from_reader(std::io::Cursor::new(&file), options()).unwrap();
+            let read = read_from_bytes(&file, options()).unwrap();
+
+            assert_eq!(probed.to_string(), read.to_string());
+            assert_eq!(probed.map_size, read.map_size);
+        }
+    }
+
+    #[test]
+    fn probe_no_image() {
+        let mut bytes = ilbm_bytes(&masked_image(0));
+
+        // Not an ILBM any more
+        bytes[8..12].copy_from_slice(b"ILBX");
+
+        assert!(matches!(
+            probe_from_reader(std::io::Cursor::new(&bytes), ReadOptions::default()),
+            Err(IlbmError::NoImage)
+        ));
+    }
+
+    #[test]
+    fn truncated_body() {
+        let bytes = ilbm_bytes(&masked_image(0));
+
+        // Cutting into the BODY used to look like there was no image at all
+        match read_from_bytes(&bytes[..bytes.len() - 10], ReadOptions::default()) {
+            Err(IlbmError::Truncated { part, offset }) => {
+                assert_eq!(part, iff::Truncation::Data);
+                assert_eq!(offset, 8);
+            }
+            other => panic!("Expected truncation, got {:?}", other.map(|i| i.to_string())),
+        }
+    }
+
+    #[test]
+    fn missing_final_padding() {
+        // An odd length chunk, with no pad byte at the end of the file, is not
+        // a truncation, so we find there is no image, as usual
+        let bytes = b"FORM\0\0\0\x058SVXx";
+        assert!(matches!(
+            read_from_bytes(bytes, ReadOptions::default()),
+            Err(IlbmError::NoImage)
+        ));
+    }
+
+    /// The chunks inside the FORM that ilbm_bytes makes
+    fn form_chunks(test: &TestImage) -> Vec<iff::IffChunk> {
+        let bytes = ilbm_bytes(test);
+        let form = iff::IffReader::new(std::io::Cursor::new(&bytes)).next().unwrap();
+        form.sub_chunks().collect()
+    }
+
+    /// Write just the chunks with these ids
+    fn write_some(iff: &mut iff::IffWriter<Vec<u8>>, chunks: &[iff::IffChunk], ids: &[&[u8; 4]]) {
+        for chunk in chunks {
+            if ids.iter().any(|&id| chunk.id() == ChunkId::new(id)) {
+                iff.write_chunk(chunk.id(), chunk.data()).unwrap();
+            }
+        }
+    }
+
+    fn read_and_probe(bytes: &[u8]) -> IlbmImage {
+        let image = read_from_bytes(bytes, ReadOptions::default()).unwrap();
+        let probed = probe_from_reader(std::io::Cursor::new(bytes), ReadOptions::default()).unwrap();
+        assert_eq!(probed.to_string(), image.to_string());
+        image
+    }
+
+    const ILBM: ChunkId = ChunkId::new(b"ILBM");
+
+    #[test]
+    fn list_with_prop() {
+        let first = masked_image(0);
+        let mut second = masked_image(0);
+        second.pixels.reverse();
+
+        let mut iff = iff::IffWriter::new(Vec::new());
+        iff.begin_list(ILBM).unwrap();
+        iff.begin_group(iff::PROP, ILBM).unwrap();
+        write_some(&mut iff, &form_chunks(&first), &[b"BMHD", b"CMAP"]);
+        iff.end_group().unwrap();
+        for test in &[&first, &second] {
+            iff.begin_form(ILBM).unwrap();
+            write_some(&mut iff, &form_chunks(test), &[b"BODY"]);
+            iff.end_group().unwrap();
+        }
+        iff.end_group().unwrap();
+        let bytes = iff.finish().unwrap();
+
+        let image = read_and_probe(&bytes);
+        let expected = read_test(&ilbm_bytes(&first), PixelFormat::Rgb).unwrap();
+        assert_eq!(image.pixels, expected.pixels);
+        assert_eq!(image.map_size, 4);
+    }
+
+    #[test]
+    fn short_bmhd_in_prop() {
+        let test = masked_image(0);
+        let chunks = form_chunks(&test);
+
+        let mut iff = iff::IffWriter::new(Vec::new());
+        iff.begin_list(ILBM).unwrap();
+        iff.begin_group(iff::PROP, ILBM).unwrap();
+        iff.write_chunk(ChunkId::new(b"BMHD"), &[0, 20, 0, 3, 0, 0]).unwrap();
+        write_some(&mut iff, &chunks, &[b"CMAP"]);
+        iff.end_group().unwrap();
+        iff.begin_form(ILBM).unwrap();
+        write_some(&mut iff, &chunks, &[b"BODY"]);
+        iff.end_group().unwrap();
+        iff.end_group().unwrap();
+        let bytes = iff.finish().unwrap();
+
+        assert!(matches!(
+            read_from_bytes(&bytes, ReadOptions::default()),
+            Err(IlbmError::InvalidHeader { .. })
+        ));
+        assert!(matches!(
+            probe_from_reader(std::io::Cursor::new(&bytes), ReadOptions::default()),
+            Err(IlbmError::InvalidHeader { .. })
+        ));
+    }
+
+    #[test]
+    fn nested_props_override() {
+        let test = masked_image(0);
+        let chunks = form_chunks(&test);
+        let cmap = |color: u8| vec![color; 12];
+
+        let mut iff = iff::IffWriter::new(Vec::new());
+        iff.begin_list(ILBM).unwrap();
+        iff.begin_group(iff::PROP, ILBM).unwrap();
+        write_some(&mut iff, &chunks, &[b"BMHD"]);
+        iff.write_chunk(ChunkId::new(b"CMAP"), &cmap(0x11)).unwrap();
+        iff.end_group().unwrap();
+
+        // A PROP for some other form type, to be ignored
+        iff.begin_group(iff::PROP, ChunkId::new(b"8SVX")).unwrap();
+        iff.write_chunk(ChunkId::new(b"CMAP"), &cmap(0x99)).unwrap();
+        iff.end_group().unwrap();
+
+        iff.begin_list(ILBM).unwrap();
+        iff.begin_group(iff::PROP, ILBM).unwrap();
+        iff.write_chunk(ChunkId::new(b"CMAP"), &cmap(0x22)).unwrap();
+        iff.end_group().unwrap();
+        iff.begin_form(ILBM).unwrap();
+        write_some(&mut iff, &chunks, &[b"BODY"]);
+        iff.end_group().unwrap();
+        iff.end_group().unwrap();
+        iff.end_group().unwrap();
+        let bytes = iff.finish().unwrap();
+
+        let image = read_and_probe(&bytes);
+        assert!(image.pixels.iter().all(|&p| p == 0x22));
+
+        // The FORM's own chunks win over any PROP
+        let mut iff = iff::IffWriter::new(Vec::new());
+        iff.begin_list(ILBM).unwrap();
+        iff.begin_group(iff::PROP, ILBM).unwrap();
+        iff.write_chunk(ChunkId::new(b"CMAP"), &cmap(0x22)).unwrap();
+        iff.end_group().unwrap();
+        iff.begin_form(ILBM).unwrap();
+        write_some(&mut iff, &chunks, &[b"BMHD", b"CMAP", b"BODY"]);
+        iff.end_group().unwrap();
+        iff.end_group().unwrap();
+        let bytes = iff.finish().unwrap();
+
+        let image = read_and_probe(&bytes);
+        assert_eq!(&image.pixels[3..6], &[255, 0, 0]);
+    }
+
+    #[test]
+    fn props_are_scoped() {
+        let chunks = form_chunks(&masked_image(0));
+
+        // The inner LIST's PROP does not apply to the FORM that follows it
+        let mut iff = iff::IffWriter::new(Vec::new());
+        iff.begin_list(ILBM).unwrap();
+        iff.begin_list(ILBM).unwrap();
+        iff.begin_group(iff::PROP, ILBM).unwrap();
+        write_some(&mut iff, &chunks, &[b"BMHD", b"CMAP"]);
+        iff.end_group().unwrap();
+        iff.end_group().unwrap();
+        iff.begin_form(ILBM).unwrap();
+        write_some(&mut iff, &chunks, &[b"BODY"]);
+        iff.end_group().unwrap();
+        iff.end_group().unwrap();
+        let bytes = iff.finish().unwrap();
+
+        assert!(matches!(
+            read_from_bytes(&bytes, ReadOptions::default()),
+            Err(IlbmError::NoHeader)
+        ));
+        assert!(matches!(
+            probe_from_reader(std::io::Cursor::new(&bytes), ReadOptions::default()),
+            Err(IlbmError::NoHeader)
+        ));
+    }
+
+    #[test]
+    fn prop_in_cat_ignored() {
+        let chunks = form_chunks(&masked_image(0));
+
+        let mut iff = iff::IffWriter::new(Vec::new());
+        iff.begin_cat(ILBM).unwrap();
+        iff.begin_group(iff::PROP, ILBM).unwrap();
+        write_some(&mut iff, &chunks, &[b"BMHD", b"CMAP"]);
+        iff.end_group().unwrap();
+        iff.begin_form(ILBM).unwrap();
+        write_some(&mut iff, &chunks, &[b"BODY"]);
+        iff.end_group().unwrap();
+        iff.end_group().unwrap();
+        let bytes = iff.finish().unwrap();
+
+        assert!(matches!(
+            read_from_bytes(&bytes, ReadOptions::default()),
+            Err(IlbmError::NoHeader)
+        ));
+        assert!(matches!(
+            probe_from_reader(std::io::Cursor::new(&bytes), ReadOptions::default()),
+            Err(IlbmError::NoHeader)
+        ));
+    }
+
+    #[test]
+    fn cat() {
+        let test = transparent_image(0x80, 6);
+
+        let mut iff = iff::IffWriter::new(Vec::new());
+        iff.begin_cat(ChunkId::new(b"    ")).unwrap();
+        iff.begin_form(ChunkId::new(b"8SVX")).unwrap();
+        iff.write_chunk(ChunkId::new(b"BODY"), &[1, 2, 3]).unwrap();
+        iff.end_group().unwrap();
+        iff.begin_form(ILBM).unwrap();
+        write_some(&mut iff, &form_chunks(&test), &[b"BMHD", b"CMAP", b"CAMG", b"BODY"]);
+        iff.end_group().unwrap();
+        iff.end_group().unwrap();
+        let bytes = iff.finish().unwrap();
+
+        let image = read_and_probe(&bytes);
+        let expected = read_test(&ilbm_bytes(&test), PixelFormat::Rgb).unwrap();
+        assert_eq!(image.pixels, expected.pixels);
+    }
+
+    #[test]
+    fn read_all() {
+        let tests = [masked_image(0), transparent_image(0x80, 6), masked_image(1)];
+
+        // Two images in a CAT, one in a LIST inside that, and one that is broken
+        let mut iff = iff::IffWriter::new(Vec::new());
+        iff.begin_cat(ILBM).unwrap();
+        for test in &tests[..2] {
+            iff.begin_form(ILBM).unwrap();
+            write_some(&mut iff, &form_chunks(test), &[b"BMHD", b"CMAP", b"CAMG", b"BODY"]);
+            iff.end_group().unwrap();
+        }
+        iff.begin_list(ILBM).unwrap();
+        iff.begin_group(iff::PROP, ILBM).unwrap();
+        write_some(&mut iff, &form_chunks(&tests[2]), &[b"BMHD", b"CMAP"]);
+        iff.end_group().unwrap();
+        iff.begin_form(ILBM).unwrap();
+        write_some(&mut iff, &form_chunks(&tests[2]), &[b"BODY"]);
+        iff.end_group().unwrap();
+        iff.begin_form(ILBM).unwrap();
+        iff.write_chunk(ChunkId::new(b"BODY"), &[]).unwrap();
+        iff.end_group().unwrap();
+        iff.end_group().unwrap();
+        iff.end_group().unwrap();
+        let bytes = iff.finish().unwrap();
+
+        let found = read_all_from_bytes(&bytes, ReadOptions::default()).unwrap();
+        assert_eq!(found.len(), 4);
+
+        for (i, test) in tests.iter().enumerate() {
+            let (position, image) = &found[i];
+            assert_eq!(position.index, i);
+            assert_eq!(&bytes[position.offset as usize..position.offset as usize + 4], b"FORM");
+
+            let expected = read_test(&ilbm_bytes(test), PixelFormat::Rgb).unwrap();
+            assert_eq!(image.as_ref().unwrap().pixels, expected.pixels);
+        }
+
+        assert_eq!(found[0].0.offset, 12);
+        assert!(matches!(found[3].1, Err(IlbmError::NoData)));
+
+        // Reading just one gives the first
+        let first = read_from_bytes(&bytes, ReadOptions::default()).unwrap();
+        assert_eq!(&first.pixels, &found[0].1.as_ref().unwrap().pixels);
+
+        let from_reader = read_all_from_reader(std::io::Cursor::new(&bytes), ReadOptions::default()).unwrap();
+        assert_eq!(from_reader.len(), 4);
+    }
+
+    #[test]
+    fn read_all_no_image() {
+        assert!(matches!(
+            read_all_from_bytes(b"FORM\0\0\0\x048SVX", ReadOptions::default()),
+            Err(IlbmError::NoImage)
+        ));
+    }
+}
@@ -0,0 +1,562 @@
+use crate::compression;
+use crate::iff::IffWriter;
+use crate::mode::{EXTRA_HALFBRITE, HAM};
+use crate::*;
+use std::io::Write;
+use std::path::Path;
+
+const ILBM: ChunkId = ChunkId::new(b"ILBM");
+const BMHD: ChunkId = ChunkId::new(b"BMHD");
+const CMAP: ChunkId = ChunkId::new(b"CMAP");
+const CAMG: ChunkId = ChunkId::new(b"CAMG");
+const DPI: ChunkId = ChunkId::new(b"DPI ");
+const BODY: ChunkId = ChunkId::new(b"BODY");
+
+/// Everything we need to decide before writing, based on the pixel format of the image
+struct Layout<'a> {
+    planes: usize,
+    masking: Masking,
+    color_map: Option<&'a ColorMap>,
+    display_mode: u32,
+}
+
+impl<'a> Layout<'a> {
+    fn new(image: &'a IlbmImage) -> Result<Layout<'a>> {
+        let Size2D(width, height) = image.size;
+
+        if width == 0 || height == 0 || width > u16::MAX as usize || height > u16::MAX as usize {
+            return Err(IlbmError::InvalidData(format!(
+                "cannot write an image of size {}",
+                image.size
+            )));
+        }
+
+        let expected = width * height * image.pixel_format.bytes_per_pixel();
+        if image.pixels.len() != expected {
+            return Err(IlbmError::InvalidData(format!(
+                "expected {} bytes of pixel data for {} {:?}, but got {}",
+                expected,
+                image.size,
+                image.pixel_format,
+                image.pixels.len()
+            )));
+        }
+
+        match image.pixel_format {
+            PixelFormat::Indexed => {
+                if image.display_mode.is_ham() {
+                    return Err(IlbmError::NotSupported("Writing indexed HAM".to_string()));
+                }
+
+                let color_map = image.color_map.as_ref().ok_or_else(|| {
+                    IlbmError::InvalidData("indexed image has no color map".to_string())
+                })?;
+
+                let max_index = image.pixels.iter().copied().max().unwrap_or(0) as usize;
+                if max_index >= color_map.len() {
+                    return Err(IlbmError::NoMapEntry {
+                        index: max_index,
+                        map_size: color_map.len(),
+                    });
+                }
+
+                // Keep the planes we were given if they are enough,
+                // otherwise use just enough for the whole color map
+                let planes = if (1..=8).contains(&image.planes) && (1 << image.planes) > max_index {
+                    image.planes
+                } else {
+                    let mut planes = 1;
+                    while planes < 8 && (1 << planes) < color_map.len() {
+                        planes += 1;
+                    }
+                    planes
+                };
+
+                // A mask plane is made from the transparent color, the only
+                // transparency indexes can have
+                Ok(Layout {
+                    planes,
+                    masking: image.masking,
+                    color_map: Some(color_map),
+                    display_mode: image.display_mode.cleaned().value(),
+                })
+            }
+
+            // Deep images have no use for HAM or halfbrite, nor for a CMAP, which
+            // readers (this one included) take to mean the planes hold indexes
+            PixelFormat::Rgb => Ok(Layout {
+                planes: 24,
+                masking: Masking::NoMask,
+                color_map: None,
+                display_mode: image.display_mode.cleaned().value() & !(HAM | EXTRA_HALFBRITE),
+            }),
+
+            // Alpha is the top 8 planes, a 1 bit mask would lose partial alpha
+            PixelFormat::Rgba => Ok(Layout {
+                planes: 32,
+                masking: Masking::NoMask,
+                color_map: None,
+                display_mode: image.display_mode.cleaned().value() & !(HAM | EXTRA_HALFBRITE),
+            }),
+        }
+    }
+}
+
+pub fn write_file<P: AsRef<Path>>(path: P, image: &IlbmImage, options: WriteOptions) -> Result<()> {
+    let file = std::fs::File::create(path)?;
+    write(std::io::BufWriter::new(file), image, options)
+}
+
+pub fn write<W: Write>(writer: W, image: &IlbmImage, options: WriteOptions) -> Result<()> {
+    let layout = Layout::new(image)?;
+
+    let mut iff = IffWriter::new(writer);
+    iff.begin_form(ILBM)?;
+
+    iff.write_chunk(BMHD, &bitmap_header(image, &layout))?;
+
+    if let Some(color_map) = layout.color_map {
+        iff.write_chunk(CMAP, &color_map_data(color_map))?;
+    }
+
+    iff.write_chunk(CAMG, &layout.display_mode.to_be_bytes())?;
+
+    if image.dpi.width() != 0 && image.dpi.height() != 0 {
+        let mut dpi = Vec::with_capacity(4);
+        dpi.extend_from_slice(&(image.dpi.width() as u16).to_be_bytes());
+        dpi.extend_from_slice(&(image.dpi.height() as u16).to_be_bytes());
+        iff.write_chunk(DPI, &dpi)?;
+    }
+
+    iff.write_chunk(BODY, &body(image, &layout, &options))?;
+
+    iff.end_group()?;
+    iff.finish()?;
+    Ok(())
+}
+
+fn masking_value(masking: Masking) -> u8 {
+    match masking {
+        Masking::NoMask => 0,
+        Masking::HasMask => 1,
+        Masking::HasTransparentColor => 2,
+        Masking::Lasso => 3,
+    }
+}
+
+/// The mirror image of read_bitmap_header
+fn bitmap_header(image: &IlbmImage, layout: &Layout) -> Vec<u8> {
+    let mut data = Vec::with_capacity(20);
+
+    data.extend_from_slice(&(image.size.width() as u16).to_be_bytes());
+    data.extend_from_slice(&(image.size.height() as u16).to_be_bytes());
+
+    // x, y position
+    data.extend_from_slice(&0i16.to_be_bytes());
+    data.extend_from_slice(&0i16.to_be_bytes());
+
+    data.push(layout.planes as u8);
+    data.push(masking_value(layout.masking));
+
+    // We only pack with ByteRun1, whatever the image was read with
+    data.push(if image.compression.is_compressed() { 1 } else { 0 });
+
+    // pad
+    data.push(0);
+
+    data.extend_from_slice(&(image.transparent_color as u16).to_be_bytes());
+
+    // Zero aspect or page size means we never had one, so pick something sensible
+    let aspect = if image.pixel_aspect.width() == 0 || image.pixel_aspect.height() == 0 {
+        Size2D(1, 1)
+    } else {
+        image.pixel_aspect
+    };
+    data.push(aspect.width() as u8);
+    data.push(aspect.height() as u8);
+
+    let page = if image.page_size.width() == 0 || image.page_size.height() == 0 {
+        image.size
+    } else {
+        image.page_size
+    };
+    data.extend_from_slice(&(page.width() as i16).to_be_bytes());
+    data.extend_from_slice(&(page.height() as i16).to_be_bytes());
+
+    data
+}
+
+fn color_map_data(color_map: &ColorMap) -> Vec<u8> {
+    let mut data = Vec::with_capacity(color_map.len() * 3);
+    for color in color_map.colors() {
+        data.push(color.red());
+        data.push(color.green());
+        data.push(color.blue());
+    }
+    data
+}
+
+/// Interleave the bitplanes, row by row, exactly as read_body_with_cmap
+/// and read_body_no_map expect to find them
+fn body(image: &IlbmImage, layout: &Layout, options: &WriteOptions) -> Vec<u8> {
+    let Size2D(width, height) = image.size;
+    let bytes_per_pixel = image.pixel_format.bytes_per_pixel();
+
+    // Bytes per row (always EVEN)
+    let row_stride = width.div_ceil(16) * 2;
+
+    let has_mask = layout.masking == Masking::HasMask;
+    let rows_per_line = layout.planes + if has_mask { 1 } else { 0 };
+    let mut body = Vec::<u8>::with_capacity(row_stride * rows_per_line * height);
+
+    // Each plane row is built here, then copied or packed into the body
+    let mut plane_row = Vec::<u8>::with_capacity(row_stride);
+    let mut push_plane_row = |values: &[u32], plane_bit: u32, body: &mut Vec<u8>| {
+        plane_row.clear();
+        push_plane_row(values, plane_bit, row_stride, &mut plane_row);
+        if image.compression.is_compressed() {
+            compression::packer(&plane_row, options.packer, body);
+        } else {
+            body.extend_from_slice(&plane_row);
+        }
+    };
+
+    for line in image.pixels.chunks(width * bytes_per_pixel) {
+        // Turn the pixels back into values, with one bit for each plane
+        let values: Vec<u32> = match image.pixel_format {
+            PixelFormat::Indexed => line.iter().map(|&p| p as u32).collect(),
+            PixelFormat::Rgb => line
+                .chunks(bytes_per_pixel)
+                .map(|p| p[0] as u32 | (p[1] as u32) << 8 | (p[2] as u32) << 16)
+                .collect(),
+            PixelFormat::Rgba => line
+                .chunks(bytes_per_pixel)
+                .map(|p| p[0] as u32 | (p[1] as u32) << 8 | (p[2] as u32) << 16 | (p[3] as u32) << 24)
+                .collect(),
+        };
+
+        for plane in 0..layout.planes {
+            push_plane_row(&values, 1 << plane, &mut body);
+        }
+
+        // Only indexed images get this far with a mask
+        if has_mask {
+            let mask: Vec<u32> = line
+                .iter()
+                .map(|&p| if p as usize == image.transparent_color { 0 } else { 1 })
+                .collect();
+            push_plane_row(&mask, 1, &mut body);
+        }
+    }
+
+    body
+}
+
+/// Gather one bit from every value into a row of plane data, high bit is leftmost
+fn push_plane_row(values: &[u32], plane_bit: u32, row_stride: usize, body: &mut Vec<u8>) {
+    let start = body.len();
+    body.resize(start + row_stride, 0);
+
+    for (x, value) in values.iter().enumerate() {
+        if value & plane_bit != 0 {
+            body[start + x / 8] |= 0x80 >> (x % 8);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn round_trip(image: &IlbmImage) -> IlbmImage {
+        let mut bytes = Vec::new();
+        write_to(&mut bytes, image, WriteOptions::default()).unwrap();
+        read_from_bytes(
+            &bytes,
+            ReadOptions {
+                pixel_format: image.pixel_format,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+
+    fn palette(size: usize) -> ColorMap {
+        ColorMap::new(
+            (0..size)
+                .map(|i| RgbValue::new(i as u8, 255 - i as u8, (i * 7) as u8))
+                .collect(),
+        )
+    }
+
+    fn indexed_image(width: usize, height: usize, planes: usize) -> IlbmImage {
+        let map_size = 1 << planes;
+        IlbmImage {
+            size: Size2D(width, height),
+            planes,
+            color_map: Some(palette(map_size)),
+            pixel_format: PixelFormat::Indexed,
+            pixels: (0..width * height)
+                .map(|i| ((i * 13 + i / width) % map_size) as u8)
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn indexed_every_plane_count() {
+        for planes in 1..=8 {
+            for &(width, height) in &[(1, 1), (15, 3), (16, 2), (17, 5), (33, 4)] {
+                let image = indexed_image(width, height, planes);
+                let read = round_trip(&image);
+
+                assert_eq!(read.planes, planes);
+                assert_eq!(read.size.width(), width);
+                assert_eq!(read.size.height(), height);
+                assert_eq!(read.pixels, image.pixels, "{} planes {}", planes, image.size);
+                assert_eq!(
+                    read.color_map.unwrap().colors(),
+                    image.color_map.as_ref().unwrap().colors()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn built_from_scratch() {
+        // Only what a caller outside the crate can use
+        let mut colors = vec![RgbValue::new(0, 0, 0), RgbValue::new(255, 255, 255)];
+        let image = IlbmImage {
+            size: Size2D::new(4, 2),
+            planes: 1,
+            color_map: Some(ColorMap::new(colors.clone())),
+            pixel_format: PixelFormat::Indexed,
+            pixels: vec![0, 1, 1, 0, 1, 0, 0, 1],
+            ..Default::default()
+        };
+
+        let read = round_trip(&image);
+        assert_eq!(read.pixels, image.pixels);
+        assert_eq!(read.color_map.as_ref().unwrap().colors(), colors.as_slice());
+
+        // Changing the palette before writing
+        colors[1] = RgbValue::new(255, 0, 0);
+        let recolored = IlbmImage {
+            color_map: Some(ColorMap::new(colors.clone())),
+            ..read
+        };
+
+        let read = round_trip(&recolored);
+        assert_eq!(read.pixels, image.pixels);
+        assert_eq!(read.color_map.unwrap().colors(), colors.as_slice());
+    }
+
+    #[test]
+    fn indexed_to_rgb() {
+        let image = indexed_image(10, 3, 3);
+
+        let path = std::env::temp_dir().join(format!("ilbm_write_rgb_{}.iff", std::process::id()));
+        write_to_file(&path, &image, WriteOptions::default()).unwrap();
+        let rgb = read_from_file(&path, ReadOptions::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Reading the same data as RGB resolves through the map
+        let map = image.color_map.as_ref().unwrap();
+        let expected: Vec<u8> = image
+            .pixels
+            .iter()
+            .flat_map(|&p| {
+                let c = map.colors()[p as usize];
+                vec![c.red(), c.green(), c.blue()]
+            })
+            .collect();
+
+        assert_eq!(rgb.pixels, expected);
+    }
+
+    #[test]
+    fn indexed_keeps_header_fields() {
+        let mut image = indexed_image(7, 7, 4);
+        image.masking = Masking::HasTransparentColor;
+        image.transparent_color = 3;
+        image.dpi = Size2D(72, 75);
+        image.pixel_aspect = Size2D(10, 11);
+        image.page_size = Size2D(320, 256);
+
+        let read = round_trip(&image);
+
+        assert_eq!(read.masking, Masking::HasTransparentColor);
+        assert_eq!(read.transparent_color, 3);
+        assert_eq!(read.dpi.width(), 72);
+        assert_eq!(read.dpi.height(), 75);
+        assert_eq!(read.pixel_aspect.width(), 10);
+        assert_eq!(read.pixel_aspect.height(), 11);
+        assert_eq!(read.page_size.width(), 320);
+        assert_eq!(read.page_size.height(), 256);
+    }
+
+    #[test]
+    fn indexed_mask() {
+        let mut image = indexed_image(11, 3, 3);
+        image.masking = Masking::HasMask;
+        image.transparent_color = 2;
+
+        let mut bytes = Vec::new();
+        write_to(&mut bytes, &image, WriteOptions::default()).unwrap();
+
+        let read = round_trip(&image);
+        assert_eq!(read.masking, Masking::HasMask);
+        assert_eq!(read.pixels, image.pixels);
+
+        // The mask plane is clear just where the transparent color is
+        let rgba = read_from_bytes(
+            &bytes,
+            ReadOptions {
+                pixel_format: PixelFormat::Rgba,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        for (index, pixel) in image.pixels.iter().zip(rgba.pixels.chunks(4)) {
+            assert_eq!(pixel[3], if *index == 2 { 0 } else { 255 });
+        }
+    }
+
+    #[test]
+    fn rgb_drops_color_map() {
+        let (width, height) = (6, 2);
+        let image = IlbmImage {
+            size: Size2D(width, height),
+            pixel_format: PixelFormat::Rgb,
+            color_map: Some(palette(4)),
+            pixels: (0..width * height * 3).map(|i| (i * 11) as u8).collect(),
+            ..Default::default()
+        };
+
+        let read = round_trip(&image);
+        assert!(read.color_map.is_none());
+        assert_eq!(read.pixels, image.pixels);
+    }
+
+    #[test]
+    fn indexed_halfbrite() {
+        let mut image = indexed_image(9, 2, 6);
+        image.display_mode = DisplayMode::new(0x80);
+
+        let read = round_trip(&image);
+        assert!(read.display_mode.is_halfbrite());
+        assert_eq!(read.pixels, image.pixels);
+    }
+
+    #[test]
+    fn indexed_minimal_planes() {
+        // Planes that cannot hold the indexes are replaced
+        let mut image = indexed_image(5, 5, 5);
+        image.planes = 2;
+        let read = round_trip(&image);
+        assert_eq!(read.planes, 5);
+        assert_eq!(read.pixels, image.pixels);
+    }
+
+    #[test]
+    fn rgb() {
+        let (width, height) = (19, 4);
+        let image = IlbmImage {
+            size: Size2D(width, height),
+            pixel_format: PixelFormat::Rgb,
+            pixels: (0..width * height * 3).map(|i| (i * 31) as u8).collect(),
+            ..Default::default()
+        };
+
+        let read = round_trip(&image);
+        assert_eq!(read.planes, 24);
+        assert!(read.color_map.is_none());
+        assert_eq!(read.pixels, image.pixels);
+    }
+
+    #[test]
+    fn rgba() {
+        let (width, height) = (21, 3);
+        let image = IlbmImage {
+            size: Size2D(width, height),
+            pixel_format: PixelFormat::Rgba,
+            pixels: (0..width * height)
+                .flat_map(|i| vec![i as u8, (i * 3) as u8, (i * 5) as u8, (i * 17) as u8])
+                .collect(),
+            ..Default::default()
+        };
+
+        // Partial alpha survives, as an alpha channel rather than a mask
+        let read = round_trip(&image);
+        assert_eq!(read.planes, 32);
+        assert_eq!(read.masking, Masking::NoMask);
+        assert_eq!(read.pixels, image.pixels);
+    }
+
+    #[test]
+    fn bad_images() {
+        let mut image = indexed_image(4, 4, 2);
+        image.pixels.pop();
+        assert!(matches!(write_to(Vec::new(), &image, WriteOptions::default()), Err(IlbmError::InvalidData(_))));
+
+        let mut image = indexed_image(4, 4, 2);
+        image.pixels[0] = 4;
+        assert!(matches!(write_to(Vec::new(), &image, WriteOptions::default()), Err(IlbmError::NoMapEntry { .. })));
+
+        let mut image = indexed_image(4, 4, 2);
+        image.color_map = None;
+        assert!(matches!(write_to(Vec::new(), &image, WriteOptions::default()), Err(IlbmError::InvalidData(_))));
+
+        let mut image = indexed_image(4, 4, 6);
+        image.display_mode = DisplayMode::ham();
+        assert!(matches!(write_to(Vec::new(), &image, WriteOptions::default()), Err(IlbmError::NotSupported(_))));
+    }
+
+    #[test]
+    fn compressed() {
+        for &packer in &[Packer::Optimal, Packer::Amiga] {
+            for planes in &[1, 4, 8] {
+                let mut image = indexed_image(96, 6, *planes);
+
+                // Give the packer some runs to work with
+                for (i, p) in image.pixels.iter_mut().enumerate() {
+                    if i % 96 < 80 {
+                        *p = 1;
+                    }
+                }
+                image.compression = Compression::ByteRun1;
+
+                let mut packed = Vec::new();
+                write_to(&mut packed, &image, WriteOptions { packer }).unwrap();
+
+                image.compression = Compression::None;
+                let mut unpacked = Vec::new();
+                write_to(&mut unpacked, &image, WriteOptions { packer }).unwrap();
+                assert!(packed.len() < unpacked.len());
+
+                image.compression = Compression::ByteRun1;
+                let read = round_trip(&image);
+                assert_eq!(read.compression, Compression::ByteRun1);
+                assert_eq!(read.pixels, image.pixels);
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_rgba() {
+        let (width, height) = (33, 5);
+        let image = IlbmImage {
+            size: Size2D(width, height),
+            compression: Compression::ByteRun1,
+            pixel_format: PixelFormat::Rgba,
+            pixels: (0..width * height)
+                .flat_map(|i| vec![(i / 8) as u8, 0, 200, if i % 5 == 0 { 0 } else { 255 }])
+                .collect(),
+            ..Default::default()
+        };
+
+        let read = round_trip(&image);
+        assert_eq!(read.pixels, image.pixels);
+    }
+}